@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::board::{MoveOutcome, SokobanBoard};
+use crate::editor::Playtest;
+use crate::levels::{LevelPack, Tile};
+use crate::settings::Settings;
+use crate::{GameLevel, GameLevelPack};
+
+// Played for packs that don't name any music
+const DEFAULT_MUSIC: &str = "music/dungeon.wav";
+// How long one track takes to fade into the next
+const CROSSFADE_SECONDS: f32 = 1.5;
+// Walking into a wall for a while only bumps every so often
+const BUMP_SECONDS: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sound {
+    Step,
+    Push,
+    // Walking into a wall or a box that won't move
+    Bump,
+    BoxOnGoal,
+    BoxOffGoal,
+    DoorOpen,
+    LevelComplete,
+}
+
+impl Sound {
+    const ALL: [Sound; 7] = [Sound::Step, Sound::Push, Sound::Bump, Sound::BoxOnGoal,
+        Sound::BoxOffGoal, Sound::DoorOpen, Sound::LevelComplete];
+
+    fn path(self) -> &'static str {
+        match self {
+            Sound::Step => "sounds/step.wav",
+            Sound::Push => "sounds/push.wav",
+            Sound::Bump => "sounds/bump.wav",
+            Sound::BoxOnGoal => "sounds/goal_on.wav",
+            Sound::BoxOffGoal => "sounds/goal_off.wav",
+            Sound::DoorOpen => "sounds/door.wav",
+            Sound::LevelComplete => "sounds/level_complete.wav",
+        }
+    }
+}
+
+// Sent by the game logic for every sound it wants played
+pub struct PlaySound(pub Sound);
+
+// Goals and switches, which react to boxes landing on them
+fn is_trigger(board: &SokobanBoard, index: usize) -> bool {
+    board.goals.contains(&index) || matches!(board.tiles[index], Tile::Switch(_))
+}
+
+// The sounds a move makes, given the board it left behind
+pub fn move_sounds(board: &SokobanBoard, outcome: MoveOutcome) -> Vec<Sound> {
+    match outcome {
+        MoveOutcome::Blocked => vec![Sound::Bump],
+        MoveOutcome::Walked { unlocked, .. } => {
+            let mut sounds = vec![Sound::Step];
+            if unlocked {
+                sounds.push(Sound::DoorOpen);
+            }
+            sounds
+        },
+        MoveOutcome::Pushed { box_from, box_to, opened_door, filled_hole, .. } => {
+            let mut sounds = vec![Sound::Push];
+            if !filled_hole && is_trigger(board, box_to) {
+                sounds.push(Sound::BoxOnGoal);
+            } else if is_trigger(board, box_from) {
+                sounds.push(Sound::BoxOffGoal);
+            }
+            if opened_door {
+                sounds.push(Sound::DoorOpen);
+            }
+            sounds
+        },
+    }
+}
+
+#[derive(Resource)]
+pub struct SoundHandles(Vec<Handle<AudioSource>>);
+
+pub fn load_sounds(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(SoundHandles(
+        Sound::ALL.iter().map(|sound| asset_server.load(sound.path())).collect()));
+}
+
+pub fn play_sounds(
+    time: Res<Time>,
+    audio: Res<Audio>,
+    settings: Res<Settings>,
+    handles: Res<SoundHandles>,
+    mut last_bump: Local<Option<Duration>>,
+    mut events: EventReader<PlaySound>) {
+
+    for PlaySound(sound) in events.iter() {
+        if *sound == Sound::Bump {
+            let now = time.elapsed();
+            if last_bump.is_some_and(|last| (now - last).as_secs_f32() < BUMP_SECONDS) {
+                continue;
+            }
+            *last_bump = Some(now);
+        }
+        if settings.sound_volume <= 0. {
+            continue;
+        }
+        let index = Sound::ALL.iter().position(|other| other == sound).unwrap();
+        audio.play_with_settings(handles.0[index].clone(),
+            PlaybackSettings::ONCE.with_volume(settings.sound_volume));
+    }
+}
+
+pub fn cue_level_complete(mut sounds: EventWriter<PlaySound>) {
+    sounds.send(PlaySound(Sound::LevelComplete));
+}
+
+// A looping track, faded in or out as the levels change
+struct Track {
+    path: String,
+    sink: Handle<AudioSink>,
+    // From 0 to 1, scaled by the music volume
+    fade: f32,
+    fading_out: bool,
+}
+
+// Every track that can be heard, the one fading in last
+#[derive(Resource, Default)]
+pub struct Music {
+    tracks: Vec<Track>,
+}
+
+impl Music {
+    // Fades the current track out and the given one in, unless it's already playing
+    fn cross_to(&mut self, path: &str, audio: &Audio, asset_server: &AssetServer,
+        audio_sinks: &Assets<AudioSink>) {
+
+        if self.tracks.last().is_some_and(|track| track.path == path && !track.fading_out) {
+            return;
+        }
+        for track in &mut self.tracks {
+            track.fading_out = true;
+        }
+        let sink = audio.play_with_settings(asset_server.load(path),
+            PlaybackSettings::LOOP.with_volume(0.));
+        self.tracks.push(Track {
+            path: path.to_string(),
+            sink: audio_sinks.get_handle(sink),
+            fade: 0.,
+            fading_out: false,
+        });
+    }
+}
+
+fn level_music(pack: Option<&LevelPack>, level: usize) -> &str {
+    pack.and_then(|pack| pack.music(level)).unwrap_or(DEFAULT_MUSIC)
+}
+
+// Starts the music of the level, or keeps it going if the last level had the
+// same. Playtests keep whatever was playing.
+#[allow(clippy::too_many_arguments)]
+pub fn play_level_music(
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    game_level: Res<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>,
+    playtest: Option<Res<Playtest>>,
+    mut music: ResMut<Music>) {
+
+    if playtest.is_none() {
+        let path = level_music(level_packs.get(&level_pack.0), game_level.0);
+        music.cross_to(path, &audio, &asset_server, &audio_sinks);
+    }
+}
+
+// Crossfades into the next level's music while the solved one is cleared
+#[allow(clippy::too_many_arguments)]
+pub fn play_next_level_music(
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    game_level: Res<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>,
+    playtest: Option<Res<Playtest>>,
+    mut music: ResMut<Music>) {
+
+    let pack = level_packs.get(&level_pack.0);
+    let has_next = pack.is_some_and(|pack| game_level.0 + 1 < pack.levels.len());
+    if playtest.is_none() && has_next {
+        music.cross_to(level_music(pack, game_level.0 + 1), &audio, &asset_server, &audio_sinks);
+    }
+}
+
+// Moves the tracks' fades along and keeps them at the music volume. Tracks
+// that faded out are stopped and dropped.
+pub fn fade_music(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    mut music: ResMut<Music>) {
+
+    let step = time.delta_seconds() / CROSSFADE_SECONDS;
+    for track in &mut music.tracks {
+        track.fade = if track.fading_out {
+            (track.fade - step).max(0.)
+        } else {
+            (track.fade + step).min(1.)
+        };
+        // Sinks only show up once the audio output picked the track up
+        if let Some(sink) = audio_sinks.get(&track.sink) {
+            sink.set_volume(track.fade * settings.music_volume);
+            if track.fading_out && track.fade <= 0. {
+                sink.stop();
+            }
+        }
+    }
+    music.tracks.retain(|track| !track.fading_out || track.fade > 0.);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::load;
+    use crate::components::Direction;
+    use crate::levels::parse_levels;
+
+    #[test]
+    fn moves_cue_their_sounds() {
+        let mut board = load("#######\n#@$.$ #\n#  .  #\n##D####");
+        let outcome = board.try_move(Direction::Up);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Bump]);
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Push, Sound::BoxOnGoal]);
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Bump]);
+        let outcome = board.try_move(Direction::Down);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Step]);
+    }
+
+    #[test]
+    fn boxes_leaving_goals_and_opening_doors_are_heard() {
+        let mut board = load("######\n#@*  #\n###D##");
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Push, Sound::BoxOffGoal]);
+
+        let mut board = load("#####\n#@$.#\n##D##");
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(move_sounds(&board, outcome), [Sound::Push, Sound::BoxOnGoal, Sound::DoorOpen]);
+    }
+
+    #[test]
+    fn music_carries_on_until_a_level_names_another() {
+        let levels = parse_levels(concat!(
+            "#####\n#@$.#\n#####\nMusic: music/a.wav\n\n",
+            "#####\n#@$.#\n#####\n\n",
+            "#####\n#@$.#\n#####\nMusic: music/b.wav\n"));
+        let pack = LevelPack { levels };
+        assert_eq!(level_music(Some(&pack), 0), "music/a.wav");
+        assert_eq!(level_music(Some(&pack), 1), "music/a.wav");
+        assert_eq!(level_music(Some(&pack), 2), "music/b.wav");
+        assert_eq!(level_music(None, 0), DEFAULT_MUSIC);
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::{HashSet, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::components::{Direction, MapSize};
+use crate::levels::{ParsedLevel, Tile};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveOutcome {
+    Blocked,
+    // Walking onto a key picks it up, and walking into a locked door with a
+    // key unlocks it
+    Walked { from: usize, to: usize, picked_key: bool, unlocked: bool },
+    // The player steps into the box's old tile, and the last box to land on a
+    // goal opens the doors. A box that drops into a hole is gone and fills it.
+    Pushed { from: usize, box_from: usize, box_to: usize, opened_door: bool, filled_hole: bool },
+}
+
+// What a walk can change on the board, for `SokobanBoard::walk_path`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct WalkState {
+    player: usize,
+    held_keys: usize,
+    unlocked: Vec<usize>,
+    keys: Vec<usize>,
+}
+
+// The rules of the game, without anything to do with drawing them
+#[derive(Component, Debug, Clone)]
+pub struct SokobanBoard {
+    pub size: MapSize,
+    pub tiles: Vec<Tile>,
+    pub player: usize,
+    pub boxes: Vec<usize>,
+    pub goals: Vec<usize>,
+    // Exits, which all open together
+    pub doors: Vec<usize>,
+    pub door_open: bool,
+    // Keys still lying around, and how many the player carries
+    pub keys: Vec<usize>,
+    pub held_keys: usize,
+    // Locked doors opened so far
+    pub unlocked: Vec<usize>,
+}
+
+impl SokobanBoard {
+    pub fn new(level: &ParsedLevel) -> Self {
+        let mut board = Self {
+            size: level.size,
+            tiles: level.tiles.clone(),
+            player: level.player,
+            boxes: level.boxes.clone(),
+            goals: level.goals.clone(),
+            doors: level.doors.clone(),
+            door_open: false,
+            keys: level.keys.clone(),
+            held_keys: 0,
+            unlocked: Vec::new(),
+        };
+        // Levels without goals start with the doors open
+        board.door_open = board.triggers_filled();
+        board
+    }
+
+    // The neighbouring tile, unless it's off the map
+    pub fn neighbour(&self, index: usize, direction: Direction) -> Option<usize> {
+        let (dx, dy) = direction.delta();
+        let x = index as i32 % self.size.width + dx;
+        let y = index as i32 / self.size.width + dy;
+        (x >= 0 && x < self.size.width && y >= 0 && y < self.size.height)
+            .then(|| (x + y * self.size.width) as usize)
+    }
+
+    pub fn has_box(&self, index: usize) -> bool {
+        self.boxes.contains(&index)
+    }
+
+    // Gates stay open while every switch of their letter has a box on it
+    pub fn gate_open(&self, letter: u8) -> bool {
+        self.tiles.iter().enumerate()
+            .filter(|(_, tile)| **tile == Tile::Switch(letter))
+            .all(|(index, _)| self.has_box(index))
+    }
+
+    // Whether a tile can be entered at all right now
+    pub fn is_open(&self, index: usize) -> bool {
+        match self.tiles[index] {
+            Tile::Floor | Tile::Switch(_) | Tile::Ice | Tile::OneWay(_) => true,
+            Tile::Door => self.door_open,
+            Tile::Gate(letter) => self.gate_open(letter),
+            Tile::Lock => self.unlocked.contains(&index),
+            Tile::Wall | Tile::Outside | Tile::Hole => false,
+        }
+    }
+
+    // One-way floors can only be entered going their way
+    pub fn can_enter(&self, index: usize, direction: Direction) -> bool {
+        match self.tiles[index] {
+            Tile::OneWay(way) => way == direction,
+            _ => true,
+        }
+    }
+
+    // Floor and opened doors, as long as nothing stands on them
+    pub fn is_free(&self, index: usize) -> bool {
+        self.is_open(index) && !self.has_box(index) && index != self.player
+    }
+
+    // Boxes are kept out of gates and locked doors, so they can't get stuck in
+    // them, and off keys, so they can't hide them
+    pub fn can_take_box(&self, index: usize) -> bool {
+        !matches!(self.tiles[index], Tile::Gate(_) | Tile::Lock) && !self.keys.contains(&index)
+            && self.is_free(index)
+    }
+
+    // Where a pushed box can go, holes included as it falls into them
+    pub fn takes_box(&self, index: usize) -> bool {
+        self.can_take_box(index) || self.tiles[index] == Tile::Hole
+    }
+
+    // Whether the box on a tile can be pushed that way
+    pub fn can_push(&self, box_index: usize, direction: Direction) -> bool {
+        self.neighbour(box_index, direction)
+            .is_some_and(|box_to| self.can_enter(box_to, direction) && self.takes_box(box_to))
+    }
+
+    pub fn goals_filled(&self) -> usize {
+        self.goals.iter().filter(|goal| self.has_box(**goal)).count()
+    }
+
+    pub fn triggers_filled(&self) -> bool {
+        self.goals.iter().all(|goal| self.has_box(*goal))
+    }
+
+    // Through a door if there are any, otherwise with every box on a goal
+    pub fn is_solved(&self) -> bool {
+        if self.doors.is_empty() {
+            self.triggers_filled()
+        } else {
+            self.door_open && self.doors.contains(&self.player)
+        }
+    }
+
+    // Where something that moved onto a tile ends up, sliding on over ice
+    // for as long as the next tile is `free`
+    fn slide(&self, mut index: usize, direction: Direction, free: impl Fn(usize) -> bool) -> usize {
+        while self.tiles[index] == Tile::Ice {
+            match self.neighbour(index, direction)
+                .filter(|next| self.can_enter(*next, direction) && free(*next)) {
+                Some(next) => index = next,
+                None => break,
+            }
+        }
+        index
+    }
+
+    pub fn try_move(&mut self, direction: Direction) -> MoveOutcome {
+        let Some(target) = self.neighbour(self.player, direction)
+            .filter(|target| self.can_enter(*target, direction)) else {
+            return MoveOutcome::Blocked;
+        };
+
+        let unlocked = self.tiles[target] == Tile::Lock && !self.unlocked.contains(&target)
+            && self.held_keys > 0;
+        if unlocked {
+            self.held_keys -= 1;
+            self.unlocked.push(target);
+        }
+
+        if self.is_free(target) {
+            let from = self.player;
+            let to = self.slide(target, direction, |index| self.is_free(index));
+            self.player = to;
+            let picked_key = self.keys.contains(&to);
+            if picked_key {
+                self.keys.retain(|key| *key != to);
+                self.held_keys += 1;
+            }
+            return MoveOutcome::Walked { from, to, picked_key, unlocked };
+        }
+
+        if !self.has_box(target) || !self.can_push(target, direction) {
+            return MoveOutcome::Blocked;
+        }
+        let Some(box_to) = self.neighbour(target, direction) else { return MoveOutcome::Blocked };
+        let box_to = self.slide(box_to, direction, |index| self.takes_box(index));
+        let from = self.player;
+        self.move_box(target, box_to);
+        // The player follows the box, without sliding even on ice
+        self.player = target;
+        let filled_hole = self.tiles[box_to] == Tile::Hole;
+        if filled_hole {
+            self.fill_hole(box_to);
+        }
+        let opened_door = !self.door_open && !self.doors.is_empty()
+            && self.triggers_filled();
+        if opened_door {
+            self.door_open = true;
+        }
+        MoveOutcome::Pushed { from, box_from: target, box_to, opened_door, filled_hole }
+    }
+
+    // The box in the hole is gone for good, and the hole is floor from now on
+    fn fill_hole(&mut self, index: usize) {
+        self.boxes.retain(|box_index| *box_index != index);
+        self.tiles[index] = Tile::Floor;
+    }
+
+    /// Shortest walk to a tile without pushing anything. Keys on the way are
+    /// picked up and used on locked doors as needed.
+    pub fn walk_path(&self, to: usize) -> Option<Vec<Direction>> {
+        // Only what a walk can change is kept per state, the moves themselves
+        // are tried out on a single scratch board
+        let state = |board: &SokobanBoard| {
+            let mut unlocked = board.unlocked.clone();
+            unlocked.sort_unstable();
+            let mut keys = board.keys.clone();
+            keys.sort_unstable();
+            WalkState { player: board.player, held_keys: board.held_keys, unlocked, keys }
+        };
+        let mut scratch = self.clone();
+        // Every state reached, with the one it was reached from and how
+        let mut visited = vec![(state(self), usize::MAX, Direction::Left)];
+        let mut seen = HashSet::from([visited[0].0.clone()]);
+        let mut queue = VecDeque::from([0]);
+        while let Some(current) = queue.pop_front() {
+            if visited[current].0.player == to {
+                let mut path = Vec::new();
+                let mut step = current;
+                while step != 0 {
+                    let (_, parent, direction) = &visited[step];
+                    path.push(*direction);
+                    step = *parent;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for direction in Direction::ALL {
+                let from = &visited[current].0;
+                scratch.player = from.player;
+                scratch.held_keys = from.held_keys;
+                scratch.unlocked.clone_from(&from.unlocked);
+                scratch.keys.clone_from(&from.keys);
+                let outcome = scratch.try_move(direction);
+                if !matches!(outcome, MoveOutcome::Walked { .. }) {
+                    // A walk never pushes, so a push tried here is taken back
+                    scratch.revert(outcome);
+                    continue;
+                }
+                let next = state(&scratch);
+                if seen.insert(next.clone()) {
+                    queue.push_back(visited.len());
+                    visited.push((next, current, direction));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn move_box(&mut self, from: usize, to: usize) {
+        if let Some(slot) = self.boxes.iter().position(|index| *index == from) {
+            self.boxes[slot] = to;
+        }
+    }
+
+    // Plays a move backwards, as needed for undo
+    pub fn revert(&mut self, outcome: MoveOutcome) {
+        match outcome {
+            MoveOutcome::Blocked => {},
+            MoveOutcome::Walked { from, to, picked_key, unlocked } => {
+                self.player = from;
+                if picked_key {
+                    self.keys.push(to);
+                    self.held_keys -= 1;
+                }
+                if unlocked {
+                    self.unlocked.retain(|lock| *lock != to);
+                    self.held_keys += 1;
+                }
+            },
+            MoveOutcome::Pushed { from, box_from, box_to, opened_door, filled_hole } => {
+                self.player = from;
+                if opened_door {
+                    self.door_open = false;
+                }
+                if filled_hole {
+                    self.tiles[box_to] = Tile::Hole;
+                    self.boxes.push(box_to);
+                }
+                self.move_box(box_to, box_from);
+            },
+        }
+    }
+
+    // Plays a reverted move forwards again, as needed for redo
+    pub fn replay(&mut self, outcome: MoveOutcome) {
+        match outcome {
+            MoveOutcome::Blocked => {},
+            MoveOutcome::Walked { to, picked_key, unlocked, .. } => {
+                self.player = to;
+                if unlocked {
+                    self.held_keys -= 1;
+                    self.unlocked.push(to);
+                }
+                if picked_key {
+                    self.keys.retain(|key| *key != to);
+                    self.held_keys += 1;
+                }
+            },
+            MoveOutcome::Pushed { box_from, box_to, opened_door, filled_hole, .. } => {
+                self.player = box_from;
+                self.move_box(box_from, box_to);
+                if filled_hole {
+                    self.fill_hole(box_to);
+                }
+                if opened_door {
+                    self.door_open = true;
+                }
+            },
+        }
+    }
+}
+
+// The board of a map that's known to be valid, for tests all over
+#[cfg(test)]
+pub fn load(map: &str) -> SokobanBoard {
+    SokobanBoard::new(&crate::levels::parse_level(map).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Index of the tile at the given column and row
+    fn at(board: &SokobanBoard, x: i32, y: i32) -> usize {
+        (x + y * board.size.width) as usize
+    }
+
+    #[test]
+    fn walks_onto_floor_and_goals() {
+        let mut board = load("#####\n#@ .#\n#$ .#\n#$  #\n#####");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Walked {
+            from: at(&board, 1, 1), to: at(&board, 2, 1), picked_key: false, unlocked: false });
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Walked {
+            from: at(&board, 2, 1), to: at(&board, 3, 1), picked_key: false, unlocked: false });
+        assert_eq!(board.player, at(&board, 3, 1));
+    }
+
+    #[test]
+    fn walls_block_the_player() {
+        let mut board = load("#####\n#@$.#\n#####");
+        assert_eq!(board.try_move(Direction::Up), MoveOutcome::Blocked);
+        assert_eq!(board.try_move(Direction::Left), MoveOutcome::Blocked);
+        assert_eq!(board.player, at(&board, 1, 1));
+    }
+
+    #[test]
+    fn pushes_move_the_box_and_the_player() {
+        let mut board = load("######\n#@$ .#\n######");
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(outcome, MoveOutcome::Pushed { from: at(&board, 1, 1),
+            box_from: at(&board, 2, 1), box_to: at(&board, 3, 1), opened_door: false, filled_hole: false });
+        assert_eq!(board.player, at(&board, 2, 1));
+        assert_eq!(board.boxes, vec![at(&board, 3, 1)]);
+
+        board.revert(outcome);
+        assert_eq!(board.player, at(&board, 1, 1));
+        assert_eq!(board.boxes, vec![at(&board, 2, 1)]);
+    }
+
+    #[test]
+    fn boxes_pushed_into_walls_stay() {
+        let mut board = load("######\n#@$#.#\n######");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+        assert_eq!(board.boxes, vec![at(&board, 2, 1)]);
+    }
+
+    #[test]
+    fn chained_boxes_cant_be_pushed() {
+        let mut board = load("#######\n#@$$ .#\n#    .#\n#######");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+        assert_eq!(board.boxes, vec![at(&board, 2, 1), at(&board, 3, 1)]);
+    }
+
+    #[test]
+    fn closed_doors_block_boxes_and_the_player() {
+        let mut board = load("#####\n#.$@D\n#####");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+
+        let mut board = load("#####\n#.@$D\n#####");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+        assert_eq!(board.boxes, vec![at(&board, 3, 1)]);
+    }
+
+    #[test]
+    fn filling_the_goals_opens_the_door() {
+        let mut board = load("######\n#@$ .D\n######");
+        board.try_move(Direction::Right);
+        assert!(!board.door_open);
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Pushed { from: at(&board, 2, 1),
+            box_from: at(&board, 3, 1), box_to: at(&board, 4, 1), opened_door: true, filled_hole: false });
+        assert!(board.door_open);
+        assert!(!board.is_solved());
+    }
+
+    #[test]
+    fn boxes_can_be_pushed_onto_an_open_door() {
+        let mut board = load("######\n#@$$.D\n#   .#\n######");
+        board.boxes = vec![at(&board, 3, 2), at(&board, 4, 1)];
+        board.door_open = true;
+        board.player = at(&board, 3, 1);
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Pushed { from: at(&board, 3, 1),
+            box_from: at(&board, 4, 1), box_to: at(&board, 5, 1), opened_door: false, filled_hole: false });
+        assert!(board.has_box(at(&board, 5, 1)));
+    }
+
+    #[test]
+    fn the_edge_of_the_map_blocks_moves() {
+        let mut board = load("#####\n#@$.D\n#####");
+        board.door_open = true;
+        board.player = at(&board, 4, 1);
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+        assert_eq!(board.player, at(&board, 4, 1));
+
+        board.boxes = vec![at(&board, 4, 1)];
+        board.player = at(&board, 3, 1);
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+    }
+
+    #[test]
+    fn walking_out_of_the_open_door_solves_the_level() {
+        let mut board = load("#####\n#@$.#\n#   D\n#####");
+        board.try_move(Direction::Right);
+        for direction in [Direction::Down, Direction::Right] {
+            board.try_move(direction);
+            assert!(!board.is_solved());
+        }
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Walked {
+            from: at(&board, 3, 2), to: at(&board, 4, 2), picked_key: false, unlocked: false });
+        assert!(board.is_solved());
+    }
+
+    #[test]
+    fn levels_without_a_door_are_solved_by_the_goals() {
+        let mut board = load("#####\n#@$.#\n#####");
+        assert!(!board.is_solved());
+        board.try_move(Direction::Right);
+        assert!(board.is_solved());
+    }
+
+    #[test]
+    fn revert_undoes_a_push_and_closes_the_door() {
+        let mut board = load("#####\n#@$.D\n#####");
+        let outcome = board.try_move(Direction::Right);
+        assert!(board.door_open);
+        board.revert(outcome);
+        assert!(!board.door_open);
+        assert_eq!(board.boxes, vec![at(&board, 2, 1)]);
+        board.replay(outcome);
+        assert!(board.door_open);
+        assert_eq!(board.boxes, vec![at(&board, 3, 1)]);
+    }
+
+    #[test]
+    fn any_open_door_is_a_way_out() {
+        let mut board = load("#D###
+#@$.D
+#####");
+        assert_eq!(board.doors, vec![at(&board, 1, 0), at(&board, 4, 1)]);
+        board.try_move(Direction::Right);
+        assert!(board.door_open);
+        board.try_move(Direction::Left);
+        board.try_move(Direction::Up);
+        assert!(board.is_solved());
+    }
+
+    #[test]
+    fn keys_open_locked_doors_for_good() {
+        let mut board = load("#######\n#@k K.#\n#  $  #\n#######");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Walked {
+            from: at(&board, 1, 1), to: at(&board, 2, 1), picked_key: true, unlocked: false });
+        assert_eq!(board.held_keys, 1);
+        board.try_move(Direction::Right);
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(outcome, MoveOutcome::Walked {
+            from: at(&board, 3, 1), to: at(&board, 4, 1), picked_key: false, unlocked: true });
+        assert_eq!(board.held_keys, 0);
+
+        board.revert(outcome);
+        assert_eq!((board.held_keys, board.unlocked.len()), (1, 0));
+        board.replay(outcome);
+        assert_eq!((board.held_keys, board.unlocked.clone()), (0, vec![at(&board, 4, 1)]));
+    }
+
+    #[test]
+    fn locked_doors_need_a_key() {
+        let mut board = load("######\n#@K.k#\n# $  #\n######");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+        assert!(board.unlocked.is_empty());
+    }
+
+    #[test]
+    fn gates_are_open_while_their_switches_hold_boxes() {
+        let mut board = load("#######\n#@$a  #\n##A####\n#  . .#\n#  $  #\n#######");
+        let gate = at(&board, 2, 2);
+        assert!(!board.is_open(gate));
+        let outcome = board.try_move(Direction::Right);
+        assert!(board.is_open(gate));
+        // Only the player goes through, boxes could get stuck in there
+        assert!(!board.can_take_box(gate));
+        board.revert(outcome);
+        assert!(!board.is_open(gate));
+    }
+
+    #[test]
+    fn walks_pick_up_the_keys_they_need() {
+        let board = load("######\n#@k$.#\n#K####\n#D#");
+        assert_eq!(board.walk_path(at(&board, 1, 2)),
+            Some(vec![Direction::Right, Direction::Left, Direction::Down]));
+        // Never by pushing a box out of the way
+        assert_eq!(board.walk_path(at(&board, 4, 1)), None);
+    }
+
+    #[test]
+    fn the_player_and_boxes_slide_over_ice() {
+        let mut board = load("#######\n#@~~ $#\n#    .#\n#######");
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Walked {
+            from: at(&board, 1, 1), to: at(&board, 4, 1), picked_key: false, unlocked: false });
+
+        // Until something stops them, or they're off the ice
+        let mut board = load("######\n#@$~~#\n#   .#\n######");
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(outcome, MoveOutcome::Pushed { from: at(&board, 1, 1),
+            box_from: at(&board, 2, 1), box_to: at(&board, 4, 1), opened_door: false, filled_hole: false });
+        board.revert(outcome);
+        assert_eq!(board.boxes, vec![at(&board, 2, 1)]);
+    }
+
+    #[test]
+    fn one_way_floors_are_only_entered_going_their_way() {
+        let mut board = load("######\n#@> .#\n# $< #\n######");
+        assert!(matches!(board.try_move(Direction::Right), MoveOutcome::Walked { .. }));
+        // Leaving them is fine any way
+        assert!(matches!(board.try_move(Direction::Right), MoveOutcome::Walked { .. }));
+        assert_eq!(board.try_move(Direction::Down), MoveOutcome::Blocked);
+        board.player = at(&board, 1, 2);
+        assert_eq!(board.try_move(Direction::Right), MoveOutcome::Blocked);
+    }
+
+    #[test]
+    fn boxes_fill_holes() {
+        let mut board = load("#######\n#@$x .#\n#  $  #\n#######");
+        let hole = at(&board, 3, 1);
+        assert_eq!(board.try_move(Direction::Up), MoveOutcome::Blocked);
+        let outcome = board.try_move(Direction::Right);
+        assert_eq!(outcome, MoveOutcome::Pushed { from: at(&board, 1, 1),
+            box_from: at(&board, 2, 1), box_to: hole, opened_door: false, filled_hole: true });
+        assert_eq!(board.boxes, vec![at(&board, 3, 2)]);
+        assert!(matches!(board.try_move(Direction::Right), MoveOutcome::Walked { .. }));
+        assert_eq!(board.player, hole);
+
+        board.player = at(&board, 2, 1);
+        board.revert(outcome);
+        assert_eq!(board.tiles[hole], Tile::Hole);
+        assert!(board.has_box(at(&board, 2, 1)));
+    }
+}
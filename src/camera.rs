@@ -0,0 +1,259 @@
+use bevy::{
+    core_pipeline::clear_color::ClearColorConfig,
+    prelude::*,
+    render::{camera::Viewport, view::RenderLayers},
+    window::{PrimaryWindow, WindowMode, WindowResized},
+};
+
+use crate::board::SokobanBoard;
+use crate::components::{MapSize, Player, WorldPosition};
+use crate::input::{Action, ActionState};
+use crate::map::map_origin;
+use crate::settings::Settings;
+
+// The game is drawn at this size, then scaled up as far as whole pixels fit
+pub const VIRTUAL_SIZE: UVec2 = UVec2::new(128, 80);
+
+// Furthest the camera zooms out before tiles get too small to make out. Maps
+// that still don't fit are scrolled instead.
+const MAX_ZOOM_OUT: f32 = 2.;
+// How quickly the camera catches up with the player, the higher the snappier
+const FOLLOW_RATE: f32 = 6.;
+
+// The camera that draws the game itself, as opposed to the UI
+#[derive(Component)]
+pub struct GameCamera;
+
+pub fn spawn_cameras(mut commands: Commands) {
+    commands.spawn((
+        Camera2dBundle {
+            transform: Transform::from_xyz(0., 0., 10.),
+            ..default()
+        },
+        // The UI is laid out over the whole window, letterbox and all
+        UiCameraConfig { show_ui: false },
+        GameCamera,
+    ));
+    commands.spawn((
+        Camera2dBundle {
+            camera: Camera { order: 1, ..default() },
+            camera_2d: Camera2d { clear_color: ClearColorConfig::None },
+            ..default()
+        },
+        // Nothing is drawn on this layer, the camera is only there for the UI
+        RenderLayers::layer(1),
+    ));
+}
+
+// Largest whole number of window pixels per game pixel, at least one
+pub fn integer_scale(window: UVec2) -> u32 {
+    (window.x / VIRTUAL_SIZE.x).min(window.y / VIRTUAL_SIZE.y).max(1)
+}
+
+// Position and size of the scaled up game, centered in the window
+pub fn letterbox(window: UVec2) -> (UVec2, UVec2) {
+    let size = (VIRTUAL_SIZE * integer_scale(window)).min(window);
+    ((window - size) / 2, size)
+}
+
+// Scales the game up by a whole number whenever the window changes size, and
+// leaves the rest of the window as bars around it. The UI gets the same scale.
+pub fn fit_to_window(
+    mut window_query: Query<&mut Window, (With<PrimaryWindow>, Changed<Window>)>,
+    mut camera_query: Query<&mut Camera, With<GameCamera>>) {
+
+    let Ok(mut window) = window_query.get_single_mut() else { return };
+    let physical = UVec2::new(window.physical_width(), window.physical_height());
+    // Minimized
+    if physical.x == 0 || physical.y == 0 {
+        return;
+    }
+    let scale = integer_scale(physical) as f64;
+    if window.scale_factor() != scale {
+        // The window keeps its size, only what a logical pixel is changes
+        window.resolution.set_scale_factor_override(Some(scale));
+        window.resolution.set_physical_resolution(physical.x, physical.y);
+    }
+
+    let (position, size) = letterbox(physical);
+    for mut camera in &mut camera_query {
+        let current = camera.viewport.as_ref()
+            .map(|viewport| (viewport.physical_position, viewport.physical_size));
+        if current != Some((position, size)) {
+            camera.viewport = Some(Viewport { physical_position: position, physical_size: size, ..default() });
+        }
+    }
+}
+
+// Moves a cursor position from the window into the viewport at `position` of
+// `size`, both in physical pixels. Cursors count logical pixels up from the
+// bottom left, as Bevy gives them. None if the cursor is in the bars around it.
+pub fn viewport_cursor(cursor: Vec2, window: UVec2, scale: f32, (position, size): (UVec2, UVec2))
+    -> Option<Vec2> {
+
+    let bottom = window.y.saturating_sub(position.y + size.y);
+    let cursor = cursor - Vec2::new(position.x as f32, bottom as f32) / scale;
+    let size = size.as_vec2() / scale;
+    (cursor.cmpge(Vec2::ZERO).all() && cursor.cmplt(size).all()).then_some(cursor)
+}
+
+// The point of the world under the cursor, as seen through the camera
+pub fn cursor_to_world(window: &Window, camera: &Camera, camera_transform: &GlobalTransform)
+    -> Option<Vec2> {
+
+    let physical = UVec2::new(window.physical_width(), window.physical_height());
+    let viewport = camera.viewport.as_ref()
+        .map_or((UVec2::ZERO, physical), |viewport| (viewport.physical_position, viewport.physical_size));
+    let cursor = viewport_cursor(window.cursor_position()?, physical, window.scale_factor() as f32, viewport)?;
+    camera.viewport_to_world(camera_transform, cursor).map(|ray| ray.origin.truncate())
+}
+
+pub fn toggle_fullscreen(
+    actions: Res<ActionState>,
+    mut settings: ResMut<Settings>,
+    mut window_query: Query<&mut Window, With<PrimaryWindow>>) {
+
+    if !actions.just_pressed(Action::Fullscreen) {
+        return;
+    }
+    let Ok(mut window) = window_query.get_single_mut() else { return };
+    settings.fullscreen = window.mode == WindowMode::Windowed;
+    window.mode = if settings.fullscreen { WindowMode::BorderlessFullscreen } else { WindowMode::Windowed };
+}
+
+// Keeps the size of the window in the settings, for the next run
+pub fn remember_window_size(
+    mut events: EventReader<WindowResized>,
+    mut settings: ResMut<Settings>,
+    window_query: Query<&Window, With<PrimaryWindow>>) {
+
+    if events.iter().last().is_none() {
+        return;
+    }
+    let Ok(window) = window_query.get_single() else { return };
+    let size = (window.physical_width(), window.physical_height());
+    if window.mode == WindowMode::Windowed && size.0 > 0 && size.1 > 0 && settings.window_size != size {
+        settings.window_size = size;
+    }
+}
+
+// Window pixels per pixel of the map, a whole number so every sprite pixel
+// comes out the same size. That's the game's own scale, or less to zoom out
+// until the map fits, but never past `MAX_ZOOM_OUT`.
+pub fn pixel_ratio(map: Vec2, scale: u32) -> u32 {
+    let view = VIRTUAL_SIZE.as_vec2() * scale as f32;
+    let fit = (view.x / map.x).min(view.y / map.y).floor() as u32;
+    let least = (scale as f32 / MAX_ZOOM_OUT).ceil() as u32;
+    fit.clamp(least.max(1), scale)
+}
+
+// Middle of the map in the world, tiles being drawn around their positions
+fn map_center(map_size: MapSize) -> Vec2 {
+    map_origin(map_size) + Vec2::new(map_size.width as f32 * 8. - 8., -(map_size.height as f32 * 8. - 8.)) / 2.
+}
+
+// Where the camera looks to keep `target` in view without showing past the
+// edges of the map. Axes the map fits in stay centered.
+pub fn camera_position(target: Vec2, center: Vec2, map: Vec2, visible: Vec2) -> Vec2 {
+    let slack = ((map - visible) / 2.).max(Vec2::ZERO);
+    center + (target - center).clamp(-slack, slack)
+}
+
+// Zooms out to fit the map in the window, and follows the player around maps
+// that are too large for that. New maps are framed straight away. The camera
+// only ever rests on whole pixels of the map, so sprites stay pixel perfect.
+pub fn frame_map(
+    time: Res<Time>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    board_query: Query<Ref<SokobanBoard>>,
+    player_query: Query<&WorldPosition, With<Player>>,
+    // Where the camera is easing to, before it's snapped to a pixel
+    mut smooth_position: Local<Vec2>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<GameCamera>>) {
+
+    let (Ok(window), Ok(board), Ok((mut transform, mut projection)))
+        = (window_query.get_single(), board_query.get_single(), camera_query.get_single_mut())
+        else { return };
+
+    // `fit_to_window` keeps the scale factor a whole number
+    let scale = window.scale_factor().round().max(1.) as u32;
+    let map = Vec2::new(board.size.width as f32, board.size.height as f32) * 8.;
+    projection.scale = scale as f32 / pixel_ratio(map, scale) as f32;
+
+    let center = map_center(board.size);
+    let target = player_query.get_single()
+        .map_or(center, |world_pos| Vec2::new(world_pos.x, world_pos.y));
+    let position = camera_position(target, center, map, VIRTUAL_SIZE.as_vec2() * projection.scale);
+
+    *smooth_position = if board.is_added() {
+        position
+    } else {
+        smooth_position.lerp(position, 1. - (-FOLLOW_RATE * time.delta_seconds()).exp())
+    };
+    transform.translation.x = smooth_position.x.round();
+    transform.translation.y = smooth_position.y.round();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_scale_by_whole_pixels_and_center_the_rest() {
+        assert_eq!(integer_scale(UVec2::new(640, 400)), 5);
+        assert_eq!(integer_scale(UVec2::new(1000, 400)), 5);
+        assert_eq!(integer_scale(UVec2::new(1920, 1080)), 13);
+        assert_eq!(integer_scale(UVec2::new(100, 60)), 1);
+        assert_eq!(letterbox(UVec2::new(1000, 400)), (UVec2::new(180, 0), UVec2::new(640, 400)));
+        assert_eq!(letterbox(UVec2::new(1920, 1080)), (UVec2::new(128, 20), UVec2::new(1664, 1040)));
+        // Windows smaller than the game crop it rather than going out of bounds
+        assert_eq!(letterbox(UVec2::new(100, 60)), (UVec2::ZERO, UVec2::new(100, 60)));
+    }
+
+    #[test]
+    fn cursors_are_measured_from_the_letterboxed_viewport() {
+        // 180 window pixels of bars on either side at a scale of 5
+        let window = UVec2::new(1000, 400);
+        let viewport = letterbox(window);
+        assert_eq!(viewport_cursor(Vec2::new(36., 10.), window, 5., viewport), Some(Vec2::new(0., 10.)));
+        assert_eq!(viewport_cursor(Vec2::new(100., 79.), window, 5., viewport), Some(Vec2::new(64., 79.)));
+        assert_eq!(viewport_cursor(Vec2::new(20., 10.), window, 5., viewport), None);
+        assert_eq!(viewport_cursor(Vec2::new(170., 10.), window, 5., viewport), None);
+
+        // 100 window pixels of bars above and below at a scale of 10, the
+        // bottom one is what's taken off
+        let window = UVec2::new(1280, 1000);
+        let viewport = letterbox(window);
+        assert_eq!(viewport_cursor(Vec2::new(5., 15.), window, 10., viewport), Some(Vec2::new(5., 5.)));
+        assert_eq!(viewport_cursor(Vec2::new(5., 5.), window, 10., viewport), None);
+    }
+
+    #[test]
+    fn maps_zoom_out_by_whole_window_pixels() {
+        // Small maps keep the game's scale
+        assert_eq!(pixel_ratio(Vec2::new(64., 48.), 5), 5);
+        // 192 pixels only fit 640 at 3 window pixels each
+        assert_eq!(pixel_ratio(Vec2::new(192., 80.), 5), 3);
+        assert_eq!(pixel_ratio(Vec2::new(192., 80.), 4), 2);
+        // Huge maps stop at half the scale, rounded up, and scroll instead
+        assert_eq!(pixel_ratio(Vec2::new(800., 400.), 5), 3);
+        assert_eq!(pixel_ratio(Vec2::new(800., 400.), 1), 1);
+    }
+
+    #[test]
+    fn the_camera_stays_within_the_map() {
+        let map = Vec2::new(400., 100.);
+        let visible = Vec2::new(200., 160.);
+        // Scrolls sideways up to the edges, stays centered vertically
+        assert_eq!(camera_position(Vec2::new(30., 40.), Vec2::ZERO, map, visible), Vec2::new(30., 0.));
+        assert_eq!(camera_position(Vec2::new(190., 0.), Vec2::ZERO, map, visible), Vec2::new(100., 0.));
+        assert_eq!(camera_position(Vec2::new(-190., 0.), Vec2::ZERO, map, visible), Vec2::new(-100., 0.));
+    }
+
+    #[test]
+    fn maps_are_centered_around_their_tiles() {
+        let size = MapSize { width: 3, height: 2 };
+        let origin = map_origin(size);
+        assert_eq!(map_center(size), origin + Vec2::new(8., -4.));
+    }
+}
@@ -1,11 +1,20 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Component, Clone, Copy)]
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq)]
 pub struct AnimationIndices {
     pub first: usize,
     pub last: usize,
 }
 
+// Frame ranges of the player atlas
+impl AnimationIndices {
+    pub const IDLE: Self = Self { first: 0, last: 7 };
+    pub const WALK: Self = Self { first: 8, last: 11 };
+    // Leaning into the box
+    pub const PUSH: Self = Self { first: 12, last: 15 };
+}
+
 #[derive(Debug, Component, Clone, Copy)]
 pub struct MapSize {
     pub width: i32,
@@ -15,12 +24,42 @@ pub struct MapSize {
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationTimer(pub Timer);
 
+// How long a single move takes
+pub const MOVE_SECONDS: f32 = 0.4;
+
 #[derive(Component, Deref, DerefMut)]
 pub struct MoveTimer(pub Timer);
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Component)]
 pub struct Player;
 
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub enum Direction { Left, Up, Right, Down }
+
+impl Direction {
+    pub const ALL: [Direction; 4] =
+        [Direction::Left, Direction::Up, Direction::Right, Direction::Down];
+
+    // Tile offset, with y growing downwards like the rows of a level
+    pub fn delta(self) -> (i32, i32) {
+        match self {
+            Direction::Left => (-1, 0),
+            Direction::Up => (0, -1),
+            Direction::Right => (1, 0),
+            Direction::Down => (0, 1),
+        }
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Up => Direction::Down,
+            Direction::Right => Direction::Left,
+            Direction::Down => Direction::Up,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default, Component)]
 pub struct WorldPosition {
     pub x: f32,
@@ -67,7 +106,34 @@ impl TilePos {
 }
 
 #[derive(Component)]
-pub enum BlockType { Wall, Box, Door }
+pub enum BlockType { Wall, Box, Door, Gate, Lock }
+
+// Frames of the tile atlas for a door swinging open, from shut to out of the way
+pub const DOOR_FRAMES: [usize; 4] = [4, 6, 7, 5];
+// How long a door takes to swing open or shut
+pub const DOOR_SECONDS: f32 = 0.3;
+
+// How far a door, gate or locked door has swung open, from 0 to 1, and which
+// way it's going
+#[derive(Component, Debug, Default)]
+pub struct DoorSwing {
+    pub open: bool,
+    pub progress: f32,
+}
+
+impl DoorSwing {
+    pub fn frame(&self) -> usize {
+        DOOR_FRAMES[(self.progress * (DOOR_FRAMES.len() - 1) as f32).round() as usize]
+    }
+}
+
+// A key lying on the floor, hidden once it's picked up
+#[derive(Component)]
+pub struct Key;
+
+// A box that fell into a hole and fills it
+#[derive(Component)]
+pub struct Sunk;
 
 /* #[derive(Bundle)]
 pub struct TileBundle {
@@ -81,20 +147,21 @@ pub struct TileBundle {
 #[derive(Component)]
 pub struct TileStorage {
     pub tiles: Vec<Option<Entity>>,
+    // Boxes that fell into holes, out of the way of whatever walks over them
+    pub sunk: Vec<Option<Entity>>,
     pub size: MapSize,
 }
 
+// Tiles a box can never leave for a goal, worked out once per level
 #[derive(Component)]
-pub struct TriggerIndices(pub Vec<usize>);
-
-#[derive(Component)]
-pub struct DoorIndex(pub usize);
+pub struct DeadSquares(pub Vec<bool>);
 
 
 impl TileStorage {
     pub fn new(size: MapSize) -> Self {
         Self {
             tiles: vec![None; (size.width * size.height) as usize],
+            sunk: vec![None; (size.width * size.height) as usize],
             size
         }
     }
@@ -105,11 +172,26 @@ impl TileStorage {
                      new_index: usize) -> Option<Entity> {
 
         if let Some(blocking) = self.tiles[new_index] {
-            return Some(blocking);
+            Some(blocking)
         } else {
             self.tiles[old_index] = None;
             self.tiles[new_index] = Some(entity);
-            return None;
+            None
+        }
+    }
+
+    pub fn sink(&mut self, commands: &mut Commands, index: usize) {
+        self.sunk[index] = self.tiles[index].take();
+        if let Some(entity) = self.sunk[index] {
+            commands.entity(entity).insert(Sunk);
+        }
+    }
+
+    // Takes a sunk box back out of its hole, as undo does
+    pub fn raise(&mut self, commands: &mut Commands, index: usize) {
+        self.tiles[index] = self.sunk[index].take();
+        if let Some(entity) = self.tiles[index] {
+            commands.entity(entity).remove::<Sunk>();
         }
     }
 }
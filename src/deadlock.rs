@@ -0,0 +1,101 @@
+use bevy::{prelude::*, tasks::{AsyncComputeTaskPool, Task}};
+use futures_lite::future;
+
+use crate::components::*;
+use crate::board::SokobanBoard;
+use crate::settings::Settings;
+use crate::solver::{DeadlockKind, SolverGrid};
+
+// Why the current position can't be solved anymore, if it can't
+#[derive(Resource, Default)]
+pub struct Deadlock(pub Option<DeadlockKind>);
+
+// Sent when a move leaves the level unsolvable
+pub struct Deadlocked(pub DeadlockKind);
+
+#[derive(Component)]
+pub struct DeadlockBanner;
+
+// A deadlock check running in the background, as looking through the corrals
+// can take a while
+#[derive(Component)]
+pub struct DeadlockTask(Task<Option<DeadlockKind>>);
+
+pub fn detect_deadlock(
+    mut commands: Commands,
+    dead_squares: Query<&DeadSquares>,
+    board_query: Query<&SokobanBoard, Changed<SokobanBoard>>,
+    task_query: Query<Entity, With<DeadlockTask>>) {
+
+    // Only look again after something moved, pushes and undos alike
+    let Ok(board) = board_query.get_single() else { return };
+    let Ok(DeadSquares(dead)) = dead_squares.get_single() else { return };
+
+    // Checks of earlier positions are of no use anymore
+    for entity in &task_query {
+        commands.entity(entity).despawn();
+    }
+    let grid = SolverGrid::from_board(board);
+    let dead = dead.clone();
+    let task = AsyncComputeTaskPool::get().spawn(async move { grid.deadlock(&dead) });
+    commands.spawn(DeadlockTask(task));
+}
+
+pub fn poll_deadlock(
+    mut commands: Commands,
+    mut deadlock: ResMut<Deadlock>,
+    mut deadlocked: EventWriter<Deadlocked>,
+    board_query: Query<(), Changed<SokobanBoard>>,
+    mut task_query: Query<(Entity, &mut DeadlockTask)>) {
+
+    // The position just changed, `detect_deadlock` starts a new check of it
+    if !board_query.is_empty() {
+        return;
+    }
+    for (entity, mut deadlock_task) in &mut task_query {
+        let Some(kind) = future::block_on(future::poll_once(&mut deadlock_task.0)) else { continue };
+        commands.entity(entity).despawn();
+
+        if let (Some(kind), None) = (kind, deadlock.0) {
+            deadlocked.send(Deadlocked(kind));
+        }
+        deadlock.0 = kind;
+    }
+}
+
+pub fn show_deadlock_banner(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    deadlock: Res<Deadlock>,
+    settings: Res<Settings>,
+    mut deadlocked: EventReader<Deadlocked>,
+    banner_query: Query<Entity, With<DeadlockBanner>>) {
+
+    if deadlocked.iter().last().is_some() && banner_query.is_empty() && settings.deadlock_warnings {
+        commands.spawn((
+            TextBundle::from_section(
+                "Stuck - undo (Z) or restart (R)",
+                TextStyle {
+                    font: asset_server.load("Minimal3x5.ttf"),
+                    font_size: 5.0,
+                    color: Color::ORANGE_RED,
+                },
+            )
+            .with_text_alignment(TextAlignment::Center)
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(2.),
+                    ..default()
+                },
+                margin: UiRect::horizontal(Val::Auto),
+                ..default()
+            }),
+            DeadlockBanner,
+        ));
+    } else if deadlock.0.is_none() || !settings.deadlock_warnings {
+        for entity in &banner_query {
+            commands.entity(entity).despawn();
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use bevy::{prelude::*, window::PrimaryWindow};
+
+use crate::camera::{cursor_to_world, pixel_ratio, GameCamera};
+use crate::components::MapSize;
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::Direction;
+use crate::input::{Action, ActionState};
+use crate::levels::{parse_level, LevelError, LevelPack, ParsedLevel};
+use crate::map::{map_origin, one_way_rotation, HOLE_COLOR, ICE_COLOR, KEY_COLOR, ONE_WAY_COLOR,
+    SWITCH_COLORS};
+use crate::mouse::tile_at;
+use crate::{load_asset_atlas, GameLevel, GameLevelPack, GameState};
+
+// Largest map that still fits the screen next to the palette
+const MAX_WIDTH: usize = 16;
+const MAX_HEIGHT: usize = 10;
+const MIN_SIZE: usize = 3;
+// The largest map with the palette above it, which the camera zooms out to fit
+const EDITOR_VIEW: Vec2 = Vec2::new(160., 100.);
+// Exported levels are appended here, relative to the assets folder
+pub const EXPORT_PATH: &str = "levels/custom.xsb";
+
+// What a click paints, in palette order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Brush {
+    Wall,
+    Floor,
+    Box,
+    Goal,
+    Player,
+    Door,
+    // Painting over a switch or gate moves on to the next letter
+    Switch,
+    Gate,
+    Key,
+    Lock,
+    Ice,
+    // Painting over a one-way floor turns it clockwise
+    OneWay,
+    Hole,
+}
+
+// Only the first ten have number keys
+const BRUSHES: [Brush; 13] = [Brush::Wall, Brush::Floor, Brush::Box, Brush::Goal, Brush::Player,
+    Brush::Door, Brush::Switch, Brush::Gate, Brush::Key, Brush::Lock, Brush::Ice, Brush::OneWay,
+    Brush::Hole];
+
+impl Brush {
+    fn sprite_index(self) -> usize {
+        match self {
+            Brush::Floor | Brush::Ice | Brush::Hole => 0,
+            Brush::Goal | Brush::Switch | Brush::Key => 1,
+            Brush::Wall => 2,
+            Brush::Box => 3,
+            Brush::Door | Brush::Gate | Brush::Lock => 4,
+            Brush::OneWay => 5,
+            // From the player atlas
+            Brush::Player => 0,
+        }
+    }
+
+    // Floor at the bottom, then what lies on it, then what stands on it
+    fn z(self) -> f32 {
+        match self {
+            Brush::Floor => 0.,
+            Brush::Ice | Brush::OneWay | Brush::Hole => 1.,
+            Brush::Goal | Brush::Switch | Brush::Key => 2.,
+            _ => 3.,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Brush::Switch | Brush::Gate => SWITCH_COLORS[0],
+            Brush::Key | Brush::Lock => KEY_COLOR,
+            Brush::Ice => ICE_COLOR,
+            Brush::OneWay => ONE_WAY_COLOR,
+            Brush::Hole => HOLE_COLOR,
+            _ => Color::WHITE,
+        }
+    }
+}
+
+// Sprite, tint and scale or rotation for one layer of a cell, the same as in
+// `spawn_map`
+fn cell_layers(cell: char) -> Vec<(Brush, Color, Transform)> {
+    let letter_color = |first| SWITCH_COLORS[(cell as u8 - first) as usize];
+    let plain = |brush: Brush| (brush, brush.color(), Transform::IDENTITY);
+    match cell {
+        '#' => vec![plain(Brush::Wall)],
+        'D' => vec![plain(Brush::Door)],
+        '$' => vec![plain(Brush::Box)],
+        '.' => vec![plain(Brush::Goal)],
+        '*' => vec![plain(Brush::Goal), plain(Brush::Box)],
+        '@' => vec![plain(Brush::Player)],
+        '+' => vec![plain(Brush::Goal), plain(Brush::Player)],
+        'a'..='c' => vec![(Brush::Switch, letter_color(b'a'), Transform::IDENTITY)],
+        'A'..='C' => vec![(Brush::Gate, letter_color(b'A'), Transform::IDENTITY)],
+        'k' => vec![(Brush::Key, KEY_COLOR, Transform::from_scale(Vec3::splat(0.6)))],
+        'K' => vec![plain(Brush::Lock)],
+        '~' => vec![plain(Brush::Ice)],
+        'x' => vec![plain(Brush::Hole)],
+        '<' | '>' | '^' | 'v' => vec![(Brush::OneWay, ONE_WAY_COLOR,
+            Transform::from_rotation(one_way_rotation(arrow_direction(cell))))],
+        _ => vec![],
+    }
+}
+
+fn arrow_direction(cell: char) -> Direction {
+    match cell {
+        '<' => Direction::Left,
+        '^' => Direction::Up,
+        'v' => Direction::Down,
+        _ => Direction::Right,
+    }
+}
+
+// The one-way floor turned clockwise, or one going right
+fn next_arrow(cell: char) -> char {
+    match cell {
+        '>' => 'v',
+        'v' => '<',
+        '<' => '^',
+        _ => '>',
+    }
+}
+
+// The next letter, or the first one
+fn next_letter(cell: char, first: char) -> char {
+    match (cell as u8).checked_sub(first as u8) {
+        Some(letter) if letter < 2 => (first as u8 + letter + 1) as char,
+        _ => first,
+    }
+}
+
+// The level being edited, as a grid of XSB characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditorMap {
+    pub width: usize,
+    pub height: usize,
+    pub cells: Vec<char>,
+}
+
+impl Default for EditorMap {
+    // An empty room with the player in the corner
+    fn default() -> Self {
+        let (width, height) = (8, 6);
+        let cells = (0..width * height).map(|index| {
+            let (x, y) = (index % width, index / width);
+            if x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+                '#'
+            } else if (x, y) == (1, 1) {
+                '@'
+            } else {
+                ' '
+            }
+        }).collect();
+        Self { width, height, cells }
+    }
+}
+
+impl EditorMap {
+    pub fn from_xsb(map: &str) -> Self {
+        let rows: Vec<Vec<char>> = map.lines().map(|row| row.chars().collect()).collect();
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let cells = rows.iter()
+            .flat_map(|row| (0..width).map(|x| row.get(x).copied().unwrap_or(' ')))
+            .collect();
+        Self { width, height: rows.len(), cells }
+    }
+
+    // Trailing spaces and empty rows around the level are left out
+    pub fn to_xsb(&self) -> String {
+        let rows: Vec<String> = self.cells.chunks(self.width.max(1))
+            .map(|row| row.iter().collect::<String>().trim_end().to_string())
+            .collect();
+        let first = rows.iter().position(|row| !row.is_empty()).unwrap_or(rows.len());
+        let last = rows.iter().rposition(|row| !row.is_empty()).map_or(first, |last| last + 1);
+        rows[first..last].join("\n")
+    }
+
+    pub fn size(&self) -> MapSize {
+        MapSize { width: self.width as i32, height: self.height as i32 }
+    }
+
+    pub fn validate(&self) -> Result<ParsedLevel, LevelError> {
+        parse_level(&self.to_xsb())
+    }
+
+    // Grows or shrinks the map from the right and bottom edges
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let width = width.clamp(MIN_SIZE, MAX_WIDTH);
+        let height = height.clamp(MIN_SIZE, MAX_HEIGHT);
+        self.cells = (0..width * height).map(|index| {
+            let (x, y) = (index % width, index / width);
+            if x < self.width && y < self.height { self.cells[x + y * self.width] } else { ' ' }
+        }).collect();
+        self.width = width;
+        self.height = height;
+    }
+
+    // Paints a tile, keeping the goal under boxes and the player. Returns
+    // whether anything changed.
+    pub fn paint(&mut self, index: usize, brush: Brush) -> bool {
+        let before = self.cells.clone();
+        let goal = matches!(self.cells[index], '.' | '*' | '+');
+        if brush == Brush::Player {
+            // There's only ever one player
+            for cell in &mut self.cells {
+                *cell = match *cell {
+                    '@' => ' ',
+                    '+' => '.',
+                    other => other,
+                };
+            }
+        }
+        self.cells[index] = match brush {
+            Brush::Wall => '#',
+            Brush::Floor => ' ',
+            Brush::Door => 'D',
+            Brush::Switch => next_letter(self.cells[index], 'a'),
+            Brush::Gate => next_letter(self.cells[index], 'A'),
+            Brush::Key => 'k',
+            Brush::Lock => 'K',
+            Brush::Ice => '~',
+            Brush::OneWay => next_arrow(self.cells[index]),
+            Brush::Hole => 'x',
+            Brush::Box if goal => '*',
+            Brush::Box => '$',
+            Brush::Player if goal => '+',
+            Brush::Player => '@',
+            Brush::Goal => match self.cells[index] {
+                '$' | '*' => '*',
+                '@' | '+' => '+',
+                _ => '.',
+            },
+        };
+        self.cells != before
+    }
+
+    // Empty cells that connect to the edge of the map, drawn faded
+    fn outside(&self) -> Vec<bool> {
+        let mut outside = vec![false; self.cells.len()];
+        let mut stack: Vec<usize> = (0..self.cells.len()).filter(|&index| {
+            let (x, y) = (index % self.width, index / self.width);
+            x == 0 || y == 0 || x + 1 == self.width || y + 1 == self.height
+        }).collect();
+        while let Some(index) = stack.pop() {
+            if outside[index] || self.cells[index] != ' ' {
+                continue;
+            }
+            outside[index] = true;
+            let (x, y) = (index % self.width, index / self.width);
+            if x > 0 { stack.push(index - 1); }
+            if x + 1 < self.width { stack.push(index + 1); }
+            if y > 0 { stack.push(index - self.width); }
+            if y + 1 < self.height { stack.push(index + self.width); }
+        }
+        outside
+    }
+}
+
+#[derive(Resource)]
+pub struct Editor {
+    pub map: EditorMap,
+    pub brush: Brush,
+    // Result of the last export, shown until the next one
+    pub notice: Option<String>,
+}
+
+// The edited level while it's being playtested, spawned instead of the pack's
+#[derive(Resource)]
+pub struct Playtest(pub ParsedLevel);
+
+#[derive(Resource)]
+pub struct EditorAtlases {
+    tiles: Handle<TextureAtlas>,
+    player: Handle<TextureAtlas>,
+}
+
+// Everything on screen that belongs to the editor
+#[derive(Component)]
+pub struct EditorEntity;
+
+#[derive(Component)]
+pub struct EditorTile;
+
+#[derive(Component)]
+pub struct PaletteSlot(Brush);
+
+#[derive(Component)]
+pub struct EditorStatus;
+
+fn palette_position(slot: usize) -> Vec2 {
+    let x = (slot as f32 - (BRUSHES.len() - 1) as f32 / 2.) * 9.;
+    Vec2::new(x, MAX_HEIGHT as f32 * 8. / 2. + 6.)
+}
+
+fn status_text(editor: &Editor) -> String {
+    let validation = match editor.map.validate() {
+        Ok(level) => format!("ok, {} boxes", level.boxes.len()),
+        Err(err) => err.to_string(),
+    };
+    let help = editor.notice.as_deref().unwrap_or("Enter: playtest  X: export");
+    format!("{}x{}  {}\n{}", editor.map.width, editor.map.height, validation, help)
+}
+
+fn export_level(map: &EditorMap) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true)
+        .open(Path::new("assets").join(EXPORT_PATH))?;
+    writeln!(file, "\n{}", map.to_xsb())
+}
+
+pub fn end_playtest(mut commands: Commands) {
+    commands.remove_resource::<Playtest>();
+}
+
+// Starts from the current level the first time, and from the last edit after that
+pub fn enter_editor(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    editor: Option<ResMut<Editor>>,
+    game_level: Res<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>) {
+
+    match editor {
+        // Redraw the map that was left behind
+        Some(mut editor) => editor.set_changed(),
+        None => {
+            let map = level_packs.get(&level_pack.0)
+                .and_then(|pack| pack.levels.get(game_level.0))
+                .map_or_else(EditorMap::default, |level| EditorMap::from_xsb(&level.map));
+            commands.insert_resource(Editor { map, brush: Brush::Wall, notice: None });
+        },
+    }
+
+    let tiles = load_asset_atlas(&asset_server, &mut texture_atlases,
+        "sprites/DungeonTiles.png", 4, 2, None, None);
+    let player = load_asset_atlas(&asset_server, &mut texture_atlases,
+        "sprites/HumanBaseIdle.png", 8, 2, Some(Vec2::splat(24.)), Some(Vec2::splat(12.)));
+
+    for (slot, brush) in BRUSHES.into_iter().enumerate() {
+        let position = palette_position(slot);
+        commands.spawn((
+            SpriteSheetBundle {
+                sprite: TextureAtlasSprite {
+                    color: brush.color(),
+                    ..TextureAtlasSprite::new(brush.sprite_index())
+                },
+                texture_atlas: if brush == Brush::Player { player.clone() } else { tiles.clone() },
+                transform: Transform::from_xyz(position.x, position.y, 3.),
+                ..default()
+            },
+            PaletteSlot(brush),
+            EditorEntity,
+        ));
+    }
+    commands.insert_resource(EditorAtlases { tiles, player });
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("Minimal3x5.ttf"),
+                font_size: 5.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Px(2.),
+                bottom: Val::Px(2.),
+                ..default()
+            },
+            ..default()
+        }),
+        EditorStatus,
+        EditorEntity,
+    ));
+}
+
+// Wherever the level left the camera, the editor has it centered. It zooms out
+// by whole window pixels, same as `frame_map` does for levels.
+pub fn frame_editor(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<GameCamera>>) {
+
+    let Ok(window) = window_query.get_single() else { return };
+    // `fit_to_window` keeps the scale factor a whole number
+    let scale = window.scale_factor().round().max(1.) as u32;
+    for (mut transform, mut projection) in &mut camera_query {
+        transform.translation.x = 0.;
+        transform.translation.y = 0.;
+        projection.scale = scale as f32 / pixel_ratio(EDITOR_VIEW, scale) as f32;
+    }
+}
+
+pub fn exit_editor(
+    mut commands: Commands,
+    entity_query: Query<Entity, With<EditorEntity>>,
+    mut projection_query: Query<&mut OrthographicProjection, With<GameCamera>>) {
+
+    for entity in &entity_query {
+        commands.entity(entity).despawn();
+    }
+    for mut projection in &mut projection_query {
+        projection.scale = 1.;
+    }
+}
+
+// Left click paints with the selected brush or picks one from the palette,
+// right click clears back to floor. Holding a button paints along the way.
+pub fn paint_map(
+    mouse_input: Res<Input<MouseButton>>,
+    mut editor: ResMut<Editor>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<GameCamera>>,
+    palette_query: Query<(&PaletteSlot, &Transform)>) {
+
+    let erase = mouse_input.pressed(MouseButton::Right);
+    if !erase && !mouse_input.pressed(MouseButton::Left) {
+        return;
+    }
+    let (Ok(window), Ok((camera, camera_transform))) = (window_query.get_single(), camera_query.get_single())
+        else { return };
+    let Some(point) = cursor_to_world(window, camera, camera_transform) else { return };
+
+    if mouse_input.just_pressed(MouseButton::Left) {
+        if let Some((PaletteSlot(brush), _)) = palette_query.iter()
+            .find(|(_, transform)| transform.translation.truncate().distance(point) < 5.) {
+            editor.brush = *brush;
+            return;
+        }
+    }
+
+    let Some(index) = tile_at(editor.map.size(), point) else { return };
+    let brush = if erase { Brush::Floor } else { editor.brush };
+    // Only redraw when the tile actually changed
+    if editor.bypass_change_detection().map.paint(index, brush) {
+        editor.notice = None;
+    }
+}
+
+pub fn editor_controls(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    mut editor: ResMut<Editor>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    if let Some(slot) = (0..BRUSHES.len()).find(|slot| actions.just_pressed(Action::Brush(*slot as u8))) {
+        editor.brush = BRUSHES[slot];
+    }
+
+    let (width, height) = (editor.map.width, editor.map.height);
+    for (direction, width, height) in [
+        (Direction::Left, width.saturating_sub(1), height),
+        (Direction::Right, width + 1, height),
+        (Direction::Up, width, height.saturating_sub(1)),
+        (Direction::Down, width, height + 1),
+    ] {
+        if actions.just_pressed(Action::Move(direction)) {
+            editor.map.resize(width, height);
+        }
+    }
+
+    if actions.just_pressed(Action::NewMap) {
+        editor.map = EditorMap::default();
+    }
+    if actions.just_pressed(Action::Export) {
+        editor.notice = Some(match editor.map.validate().map_err(|err| err.to_string())
+            .and_then(|_| export_level(&editor.map).map_err(|err| err.to_string())) {
+            Ok(()) => format!("Added to {}", EXPORT_PATH),
+            Err(err) => format!("Not exported: {}", err),
+        });
+    }
+
+    if actions.just_pressed(Action::Confirm) {
+        match editor.map.validate() {
+            Ok(level) => {
+                commands.insert_resource(Playtest(level));
+                next_state.set(GameState::Starting);
+            },
+            Err(_) => editor.notice = Some(String::from("Fix the level to playtest it")),
+        }
+    } else if actions.just_pressed(Action::Pause) {
+        next_state.set(GameState::MainMenu);
+    }
+}
+
+pub fn draw_editor(
+    mut commands: Commands,
+    editor: Res<Editor>,
+    atlases: Res<EditorAtlases>,
+    tile_query: Query<Entity, With<EditorTile>>,
+    mut palette_query: Query<(&PaletteSlot, &mut TextureAtlasSprite)>,
+    mut status_query: Query<&mut Text, With<EditorStatus>>) {
+
+    if !editor.is_changed() {
+        return;
+    }
+
+    for entity in &tile_query {
+        commands.entity(entity).despawn();
+    }
+    let map = &editor.map;
+    let origin = map_origin(map.size());
+    let outside = map.outside();
+    for (index, &cell) in map.cells.iter().enumerate() {
+        let x = origin.x + (index % map.width) as f32 * 8.;
+        let y = origin.y - (index / map.width) as f32 * 8.;
+        let mut layers = vec![(Brush::Floor, Color::WHITE, Transform::IDENTITY)];
+        layers.extend(cell_layers(cell));
+        for (brush, color, transform) in layers {
+            let mut sprite = TextureAtlasSprite { color, ..TextureAtlasSprite::new(brush.sprite_index()) };
+            if outside[index] {
+                sprite.color.set_a(0.25);
+            }
+            commands.spawn((
+                SpriteSheetBundle {
+                    sprite,
+                    texture_atlas: if brush == Brush::Player {
+                        atlases.player.clone()
+                    } else {
+                        atlases.tiles.clone()
+                    },
+                    transform: transform.with_translation(Vec3::new(x, y, brush.z())),
+                    ..default()
+                },
+                EditorTile,
+                EditorEntity,
+            ));
+        }
+    }
+
+    for (PaletteSlot(brush), mut sprite) in &mut palette_query {
+        sprite.color.set_a(if *brush == editor.brush { 1. } else { 0.4 });
+    }
+    for mut text in &mut status_query {
+        text.sections[0].value = status_text(&editor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn painting_keeps_goals_and_a_single_player() {
+        let mut map = EditorMap::from_xsb("#####\n#@ .#\n#####");
+        assert!(map.paint(8, Brush::Box));
+        assert!(map.paint(7, Brush::Player));
+        assert_eq!(map.to_xsb(), "#####\n# @*#\n#####");
+        assert!(map.paint(8, Brush::Player));
+        assert_eq!(map.to_xsb(), "#####\n#  +#\n#####");
+        assert!(!map.paint(0, Brush::Wall));
+    }
+
+    #[test]
+    fn painting_switches_again_moves_on_to_the_next_letter() {
+        let mut map = EditorMap::from_xsb("#####\n#@  #\n#####");
+        for expected in ['a', 'b', 'c', 'a'] {
+            map.paint(7, Brush::Switch);
+            assert_eq!(map.cells[7], expected);
+        }
+        map.paint(7, Brush::Gate);
+        assert_eq!(map.cells[7], 'A');
+        for expected in ['>', 'v', '<', '^', '>'] {
+            map.paint(7, Brush::OneWay);
+            assert_eq!(map.cells[7], expected);
+        }
+    }
+
+    #[test]
+    fn resizing_keeps_the_top_left_corner() {
+        let mut map = EditorMap::default();
+        map.resize(10, 2);
+        assert_eq!((map.width, map.height), (10, MIN_SIZE));
+        assert_eq!(map.to_xsb(), "########\n#@     #\n#      #");
+        map.resize(100, 100);
+        assert_eq!((map.width, map.height), (MAX_WIDTH, MAX_HEIGHT));
+    }
+
+    #[test]
+    fn the_editor_zooms_out_by_whole_window_pixels() {
+        // Four window pixels per map pixel instead of five fit it all
+        assert_eq!(pixel_ratio(EDITOR_VIEW, 5), 4);
+        assert_eq!(pixel_ratio(EDITOR_VIEW, 13), 10);
+    }
+
+    #[test]
+    fn the_default_map_needs_a_box_and_goal_to_be_valid() {
+        let mut map = EditorMap::default();
+        assert!(map.validate().is_err());
+        assert!(map.paint(map.width * 2 + 2, Brush::Box));
+        assert!(map.validate().is_err());
+        assert!(map.paint(map.width * 2 + 4, Brush::Goal));
+        let level = map.validate().unwrap();
+        assert_eq!((level.size.width, level.size.height), (8, 6));
+        assert_eq!(level.boxes.len(), 1);
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+
+use crate::audio::PlaySound;
+use crate::board::SokobanBoard;
+use crate::components::MOVE_SECONDS;
+use crate::hint::Autoplay;
+use crate::history::MoveHistory;
+use crate::hud::{tick_level_timer, LevelStats};
+use crate::input::ActionState;
+use crate::levels::{check_level_pack, Level, LevelPack, ParsedLevel};
+use crate::map::{spawn_map, transition_map};
+use crate::player::{player_move, win_condition};
+use crate::replay::{drive_replay, record_lurd, LurdError, Replay, SPEEDS};
+use crate::save::{SaveData, SavePath};
+use crate::solver::{Push, SolveError, SolverGrid, MAX_STATES};
+use crate::tween::{animate_tweens, start_tweens};
+use crate::{GameLevel, GameLevelPack, GameState};
+
+// Game time that passes with every update, regardless of how long it really took
+const FRAME: Duration = Duration::from_millis(50);
+// Updates allowed on top of the replay itself, for the level transition
+const SPARE_FRAMES: usize = 100;
+
+// The least of the game that still plays a level: no window, rendering or input
+fn headless_app(pack: LevelPack, level: usize, replay: Replay) -> App {
+    let mut app = App::new();
+    app
+        .add_plugins(MinimalPlugins)
+        // Sprites are still loaded by `spawn_map`, they just never get drawn
+        .add_plugin(AssetPlugin::default())
+        .add_asset::<TextureAtlas>()
+        .add_asset::<LevelPack>()
+        .add_state::<GameState>()
+        .insert_resource(GameLevel(level))
+        .init_resource::<MoveHistory>()
+        .init_resource::<Autoplay>()
+        .init_resource::<LevelStats>()
+        .init_resource::<ActionState>()
+        .insert_resource(replay)
+        // Sounds are cued all the same, nothing plays them
+        .add_event::<PlaySound>()
+        .add_system(spawn_map.in_schedule(OnEnter(GameState::Starting)))
+        .add_system(transition_map.in_set(OnUpdate(GameState::Starting)))
+        .add_systems((
+                drive_replay.before(player_move),
+                player_move,
+                start_tweens.after(player_move),
+                animate_tweens.after(start_tweens),
+                win_condition.after(player_move),
+                tick_level_timer.before(win_condition),
+            ).in_set(OnUpdate(GameState::Playing)));
+
+    // The pack is already loaded, so skip straight past the loading screen
+    let handle = app.world.resource_mut::<Assets<LevelPack>>().add(pack);
+    app.insert_resource(GameLevelPack(handle));
+    app.insert_resource(NextState(Some(GameState::Starting)));
+    app
+}
+
+/// Plays a LURD solution on a level of the pack, with the same systems that
+/// play it in the game, and returns whether it solves the level.
+pub fn verify_solution(levels: &[Level], level: usize, lurd: &str) -> Result<bool, LurdError> {
+    let mut replay = Replay::new(lurd)?;
+    replay.speed = SPEEDS.len() - 1;
+    let frames_per_step = (MOVE_SECONDS / SPEEDS[replay.speed] / FRAME.as_secs_f32()).ceil() as usize + 1;
+    let max_frames = replay.steps.len() * frames_per_step + SPARE_FRAMES;
+
+    let mut app = headless_app(LevelPack { levels: levels.to_vec() }, level, replay);
+    let start = Instant::now();
+    for frame in 1..=max_frames {
+        app.insert_resource(TimeUpdateStrategy::ManualInstant(start + FRAME * frame as u32));
+        app.update();
+
+        if let Some(err) = app.world.get_resource::<Replay>().and_then(|replay| replay.error) {
+            return Err(err);
+        }
+        match app.world.resource::<State<GameState>>().0 {
+            GameState::NextLevel => return Ok(true),
+            // `drive_replay` hands the controls back once it ran out of moves
+            GameState::Playing if !app.world.contains_resource::<Replay>() => return Ok(false),
+            _ => {},
+        }
+    }
+    Ok(false)
+}
+
+// The solver's pushes written out in LURD, with the walks in between and the
+// way out of the nearest door
+fn solution_lurd(level: &ParsedLevel, pushes: &[Push]) -> Option<String> {
+    let mut board = SokobanBoard::new(level);
+    let mut moves = Vec::new();
+    for push in pushes {
+        let grid = SolverGrid::from_board(&board);
+        let behind = grid.step(push.box_index, push.direction.opposite())?;
+        let mut path = board.walk_path(behind)?;
+        path.push(push.direction);
+        moves.extend(path.into_iter().map(|direction| board.try_move(direction)));
+    }
+    if !board.doors.is_empty() {
+        let path = board.doors.iter()
+            .filter_map(|door| board.walk_path(*door))
+            .min_by_key(Vec::len)?;
+        moves.extend(path.into_iter().map(|direction| board.try_move(direction)));
+    }
+    Some(record_lurd(&moves, board.size.width))
+}
+
+// Checks that every level of a pack can be solved, and that the solutions
+// stored in the save file still solve them. Prints a line per level and
+// returns whether all of them passed.
+pub fn verify_level_pack(pack_path: &str) -> bool {
+    let save_data = SavePath::from_env().0.as_deref().map(SaveData::load).unwrap_or_default();
+    let progress = save_data.pack(pack_path);
+
+    check_level_pack(&Path::new("assets").join(pack_path), pack_path, |levels, index, parsed| {
+        let name = levels[index].name(index);
+        let mut solutions = Vec::new();
+        let grid = SolverGrid::from_board(&SokobanBoard::new(&parsed));
+        match grid.solve(MAX_STATES) {
+            Ok(pushes) => solutions.extend(solution_lurd(&parsed, &pushes)
+                .map(|lurd| ("solver's solution", lurd))),
+            Err(SolveError::Unsolvable) => {
+                eprintln!("{pack_path}: {name} can't be solved");
+                return false;
+            },
+            // Needs a stored solution then
+            Err(SolveError::TooManyStates | SolveError::NeedsKeys) => {},
+        }
+        let stored = progress.and_then(|progress| progress.best.get(&index))
+            .map(|best| best.solution.clone())
+            .filter(|solution| !solution.is_empty());
+        solutions.extend(stored.map(|lurd| ("stored solution", lurd)));
+
+        if solutions.is_empty() {
+            eprintln!("{pack_path}: {name}: the solver couldn't solve it and there's no stored solution");
+            return false;
+        }
+
+        let mut solved = true;
+        for (kind, lurd) in &solutions {
+            match verify_solution(levels, index, lurd) {
+                Ok(true) => {},
+                Ok(false) => {
+                    eprintln!("{pack_path}: {name}: the {kind} doesn't solve it");
+                    solved = false;
+                },
+                Err(err) => {
+                    eprintln!("{pack_path}: {name}: the {kind} is invalid, {err}");
+                    solved = false;
+                },
+            }
+        }
+        if solved {
+            let kinds: Vec<&str> = solutions.iter().map(|(kind, _)| *kind).collect();
+            println!("{pack_path}: {name} ok ({})", kinds.join(", "));
+        }
+        solved
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::levels::{parse_level, parse_levels};
+
+    const PACK: &str = "#######\n#@ $ .#\n#######\n\n###D##\n#@ $.#\n######\n";
+
+    #[test]
+    fn solutions_are_played_through_the_game_systems() {
+        let levels = parse_levels(PACK);
+        assert_eq!(verify_solution(&levels, 0, "rRR"), Ok(true));
+        assert_eq!(verify_solution(&levels, 0, "rR"), Ok(false));
+        assert!(verify_solution(&levels, 0, "rx").is_err());
+        // Pushes have to be written in uppercase, and only pushes
+        assert_eq!(verify_solution(&levels, 0, "rrr"),
+            Err(LurdError::WrongCase { character: 'r', step: 2 }));
+        // Door levels are only left through the door
+        assert_eq!(verify_solution(&levels, 1, "rR"), Ok(false));
+        assert_eq!(verify_solution(&levels, 1, "rRu"), Ok(true));
+    }
+
+    #[test]
+    fn solver_solutions_walk_out_of_the_door() {
+        let parsed = parse_level("###D##\n#@ $.#\n######").unwrap();
+        let pushes = SolverGrid::from_board(&SokobanBoard::new(&parsed)).solve(MAX_STATES).unwrap();
+        assert_eq!(solution_lurd(&parsed, &pushes).as_deref(), Some("rRu"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, tasks::{AsyncComputeTaskPool, Task}};
+use futures_lite::future;
+
+use crate::board::SokobanBoard;
+use crate::components::{MoveTimer, Player};
+use crate::input::{Action, ActionState};
+use crate::replay::Replay;
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::Direction;
+use crate::solver::*;
+
+// Moves the game plays on its own, fed to `player_move` instead of the keyboard
+#[derive(Resource, Default)]
+pub struct Autoplay {
+    pub pushes: VecDeque<Push>,
+    pub steps: VecDeque<Direction>,
+    // Walk out of the door once all pushes are done
+    pub exit: bool,
+}
+
+impl Autoplay {
+    pub fn clear(&mut self) {
+        *self = Autoplay::default();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.pushes.is_empty() || !self.steps.is_empty() || self.exit
+    }
+}
+
+// A solver search running in the background
+#[derive(Component)]
+pub struct SolverTask {
+    task: Task<Result<Vec<Push>, SolveError>>,
+    solve_all: bool,
+}
+
+pub fn request_hint(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    mut autoplay: ResMut<Autoplay>,
+    solver_tasks: Query<&SolverTask>,
+    replay: Option<Res<Replay>>,
+    board_query: Query<&SokobanBoard>) {
+
+    // Solve plays the whole solution, Hint just the next push
+    let solve_all = actions.just_pressed(Action::Solve);
+    if !(solve_all || actions.just_pressed(Action::Hint)) || !solver_tasks.is_empty()
+        || replay.is_some() {
+        return;
+    }
+
+    let Ok(board) = board_query.get_single() else { return };
+
+    autoplay.clear();
+    let grid = SolverGrid::from_board(board);
+    let task = AsyncComputeTaskPool::get().spawn(async move { grid.solve(MAX_STATES) });
+    commands.spawn(SolverTask { task, solve_all });
+}
+
+pub fn poll_solver(
+    mut commands: Commands,
+    mut autoplay: ResMut<Autoplay>,
+    mut solver_tasks: Query<(Entity, &mut SolverTask)>) {
+
+    for (entity, mut solver_task) in &mut solver_tasks {
+        let Some(solution) = future::block_on(future::poll_once(&mut solver_task.task))
+            else { continue };
+        commands.entity(entity).despawn();
+
+        match solution {
+            Ok(pushes) if solver_task.solve_all => {
+                autoplay.pushes = pushes.into();
+                autoplay.exit = true;
+            },
+            Ok(pushes) => autoplay.pushes.extend(pushes.first()),
+            Err(SolveError::Unsolvable) => info!("The level can't be solved from this position"),
+            Err(SolveError::TooManyStates) => info!("Gave up looking for a solution"),
+            Err(SolveError::NeedsKeys) => info!("No solution without unlocking doors first"),
+        }
+    }
+}
+
+// Turns the next planned push into the walk there and the push itself,
+// based on where everything is right now
+pub fn plan_autoplay(
+    mut autoplay: ResMut<Autoplay>,
+    player_query: Query<&MoveTimer, With<Player>>,
+    board_query: Query<&SokobanBoard>) {
+
+    if !autoplay.steps.is_empty() || !autoplay.is_active() {
+        return;
+    }
+    let (Ok(move_cooldown), Ok(board)) = (player_query.get_single(), board_query.get_single())
+        else { return };
+    if !move_cooldown.finished() {
+        return;
+    }
+
+    let grid = SolverGrid::from_board(board);
+    let path = if let Some(push) = autoplay.pushes.pop_front() {
+        grid.step(push.box_index, push.direction.opposite())
+            .and_then(|behind| board.walk_path(behind))
+            .map(|mut path| {
+                path.push(push.direction);
+                path
+            })
+    } else {
+        autoplay.exit = false;
+        // To the nearest door, they're only walkable once open
+        board.doors.iter()
+            .filter_map(|door| board.walk_path(*door))
+            .min_by_key(Vec::len)
+    };
+
+    match path {
+        Some(path) => autoplay.steps = path.into(),
+        // The board changed under the plan, e.g. through an undo
+        None => autoplay.clear(),
+    }
+}
@@ -0,0 +1,110 @@
+use bevy::prelude::*;
+
+use crate::board::{MoveOutcome, SokobanBoard};
+use crate::components::*;
+use crate::hud::LevelStats;
+use crate::input::{Action, ActionState};
+use crate::replay::Replay;
+use crate::player::move_entity;
+
+// A single step of the player, with everything needed to play it backwards
+#[derive(Debug, Clone, Copy)]
+pub struct MoveRecord {
+    pub outcome: MoveOutcome,
+}
+
+#[derive(Resource, Default)]
+pub struct MoveHistory {
+    pub undo: Vec<MoveRecord>,
+    pub redo: Vec<MoveRecord>,
+}
+
+impl MoveHistory {
+    pub fn record(&mut self, record: MoveRecord) {
+        self.undo.push(record);
+        // A fresh move invalidates whatever was undone before it
+        self.redo.clear();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn undo_move(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    replay: Option<Res<Replay>>,
+    mut history: ResMut<MoveHistory>,
+    mut stats: ResMut<LevelStats>,
+    mut player: Query<(Entity, &mut WorldPosition, &mut TilePos, &mut MoveTimer), With<Player>>,
+    mut blocking_tiles_query: Query<(&mut TilePos, &mut WorldPosition), Without<Player>>,
+    mut board_query: Query<&mut SokobanBoard>,
+    mut map_tiles_query: Query<&mut TileStorage>) {
+
+    let undo = actions.just_pressed(Action::Undo);
+    let redo = actions.just_pressed(Action::Redo) && !undo;
+
+    // Replays are played from the start, without going back
+    if (!undo && !redo) || replay.is_some() {
+        return;
+    }
+
+    let Ok((player_entity, mut world_pos, mut tile_pos, mut move_cooldown))
+        = player.get_single_mut() else { return };
+
+    // Let the current step finish animating first, same as regular moves
+    if !move_cooldown.finished() {
+        return;
+    }
+
+    let mut board = board_query.single_mut();
+    let mut map_tiles = map_tiles_query.single_mut();
+
+    let record = if undo {
+        let Some(record) = history.undo.pop() else { return };
+        board.revert(record.outcome);
+        history.redo.push(record);
+        record
+    } else {
+        let Some(record) = history.redo.pop() else { return };
+        board.replay(record.outcome);
+        history.undo.push(record);
+        record
+    };
+
+    // Undone moves no longer count towards the level's score
+    if undo {
+        stats.uncount(record.outcome);
+    } else {
+        stats.count(record.outcome);
+    }
+
+    match record.outcome {
+        MoveOutcome::Blocked => {},
+        MoveOutcome::Walked { from, to, .. } => {
+            let index = if undo { from } else { to };
+            move_entity(&mut map_tiles, player_entity, &mut tile_pos, &mut world_pos, index);
+        },
+        MoveOutcome::Pushed { from, box_from, box_to, filled_hole, .. } => {
+            // Whichever is in the way moves first
+            if undo {
+                move_entity(&mut map_tiles, player_entity, &mut tile_pos, &mut world_pos, from);
+            }
+            let (box_start, box_end) = if undo { (box_to, box_from) } else { (box_from, box_to) };
+            if undo && filled_hole {
+                map_tiles.raise(&mut commands, box_to);
+            }
+            let box_entity = map_tiles.tiles[box_start].unwrap();
+            let (mut box_pos, mut box_world_pos)
+                = blocking_tiles_query.get_mut(box_entity).unwrap();
+            move_entity(&mut map_tiles, box_entity, &mut box_pos, &mut box_world_pos, box_end);
+            if !undo && filled_hole {
+                map_tiles.sink(&mut commands, box_to);
+            }
+            if !undo {
+                move_entity(&mut map_tiles, player_entity, &mut tile_pos, &mut world_pos, box_from);
+            }
+        },
+    }
+
+    // Restart the cooldown, the entities are tweened back meanwhile
+    move_cooldown.reset();
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::board::{MoveOutcome, SokobanBoard};
+use crate::settings::Settings;
+use crate::GameLevel;
+
+// Counters for the level being played, shown in the HUD
+#[derive(Resource, Default)]
+pub struct LevelStats {
+    pub moves: u32,
+    pub pushes: u32,
+    pub elapsed: Duration,
+    // Stops the clock once the level is won
+    pub solved: bool,
+}
+
+impl LevelStats {
+    // Every move counts, and pushes on top of that
+    pub fn count(&mut self, outcome: MoveOutcome) {
+        if outcome != MoveOutcome::Blocked {
+            self.moves += 1;
+        }
+        if matches!(outcome, MoveOutcome::Pushed { .. }) {
+            self.pushes += 1;
+        }
+    }
+
+    // Takes back an undone move
+    pub fn uncount(&mut self, outcome: MoveOutcome) {
+        if outcome != MoveOutcome::Blocked {
+            self.moves -= 1;
+        }
+        if matches!(outcome, MoveOutcome::Pushed { .. }) {
+            self.pushes -= 1;
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Hud;
+
+// Minutes and seconds, e.g. 1:05
+pub fn format_time(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Boxes on goals out of all goals, left out for levels without any
+fn goals_text(board: Option<&SokobanBoard>) -> String {
+    match board {
+        Some(board) if !board.goals.is_empty() =>
+            format!("  Goals {}/{}", board.goals_filled(), board.goals.len()),
+        _ => String::new(),
+    }
+}
+
+fn hud_text(level: usize, stats: &LevelStats, board: Option<&SokobanBoard>) -> String {
+    format!("Level {}  {}{}\nMoves {}  Pushes {}",
+        level + 1, format_time(stats.elapsed), goals_text(board), stats.moves, stats.pushes)
+}
+
+pub fn spawn_hud(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    game_level: Res<GameLevel>) {
+
+    commands.spawn((
+        TextBundle::from_section(
+            hud_text(game_level.0, &LevelStats::default(), None),
+            TextStyle {
+                font: asset_server.load("Minimal3x5.ttf"),
+                font_size: 5.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                left: Val::Px(2.),
+                bottom: Val::Px(2.),
+                ..default()
+            },
+            ..default()
+        }),
+        Hud,
+    ));
+}
+
+pub fn tick_level_timer(time: Res<Time>, mut stats: ResMut<LevelStats>) {
+    if !stats.solved {
+        stats.elapsed += time.delta();
+    }
+}
+
+pub fn reset_level_stats(mut stats: ResMut<LevelStats>) {
+    *stats = LevelStats::default();
+}
+
+pub fn update_hud(
+    stats: Res<LevelStats>,
+    game_level: Res<GameLevel>,
+    settings: Res<Settings>,
+    board_query: Query<Ref<SokobanBoard>>,
+    mut hud_query: Query<(&mut Text, &mut Visibility), With<Hud>>) {
+
+    let board = board_query.get_single().ok();
+    let board_changed = board.as_ref().is_some_and(|board| board.is_changed());
+    for (mut text, mut visibility) in &mut hud_query {
+        if stats.is_changed() || game_level.is_changed() || board_changed {
+            text.sections[0].value = hud_text(game_level.0, &stats, board.as_deref());
+        }
+        *visibility = if settings.show_hud { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::load;
+    use crate::components::Direction;
+
+    #[test]
+    fn times_show_minutes_and_padded_seconds() {
+        assert_eq!(format_time(Duration::ZERO), "0:00");
+        assert_eq!(format_time(Duration::from_millis(65_900)), "1:05");
+        assert_eq!(format_time(Duration::from_secs(600)), "10:00");
+    }
+
+    #[test]
+    fn levels_are_numbered_from_one() {
+        let stats = LevelStats { moves: 12, pushes: 3, ..default() };
+        assert_eq!(hud_text(0, &stats, None), "Level 1  0:00\nMoves 12  Pushes 3");
+    }
+
+    #[test]
+    fn goals_count_the_boxes_on_them() {
+        let mut board = load("#######\n#@$ .*#\n#######");
+        assert_eq!(goals_text(Some(&board)), "  Goals 1/2");
+        board.try_move(Direction::Right);
+        board.try_move(Direction::Right);
+        assert_eq!(goals_text(Some(&board)), "  Goals 2/2");
+        // Nothing to count without goals
+        let board = load("####\n#@ D\n####");
+        assert_eq!(goals_text(Some(&board)), "");
+    }
+}
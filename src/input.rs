@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashSet};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::components::Direction;
+use crate::settings::Settings;
+
+// How far the stick has to be tilted to count as a move
+const STICK_THRESHOLD: f32 = 0.5;
+
+// What the player wants to do, whichever key or button they used for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+    Move(Direction),
+    Undo,
+    Redo,
+    Restart,
+    Confirm,
+    Pause,
+    Hint,
+    Solve,
+    LevelSelect,
+    Replay,
+    Fullscreen,
+    // Picks the editor's palette slot with this number
+    Brush(u8),
+    NewMap,
+    Export,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputBinding {
+    Key(KeyCode),
+    // The key while either shift key is held
+    Shift(KeyCode),
+    // The key while either alt key is held
+    Alt(KeyCode),
+    Button(GamepadButtonType),
+}
+
+// Which inputs trigger which action, as read from the settings file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "SavedBindings")]
+pub struct Bindings(pub BTreeMap<Action, Vec<InputBinding>>);
+
+// `Bindings` as written in the file, which may predate some actions
+#[derive(Deserialize)]
+struct SavedBindings(BTreeMap<Action, Vec<InputBinding>>);
+
+// Actions missing from an older settings file keep their default inputs
+impl From<SavedBindings> for Bindings {
+    fn from(SavedBindings(bindings): SavedBindings) -> Self {
+        let mut defaults = Self::default();
+        defaults.0.extend(bindings);
+        defaults
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use GamepadButtonType::*;
+        use InputBinding::{Alt, Button, Key, Shift};
+
+        Self(BTreeMap::from([
+            // Arrows, WASD and the vi keys
+            (Action::Move(Direction::Left),
+                vec![Key(KeyCode::Left), Key(KeyCode::A), Key(KeyCode::H), Button(DPadLeft)]),
+            (Action::Move(Direction::Up),
+                vec![Key(KeyCode::Up), Key(KeyCode::W), Key(KeyCode::K), Button(DPadUp)]),
+            (Action::Move(Direction::Right),
+                vec![Key(KeyCode::Right), Key(KeyCode::D), Key(KeyCode::L), Button(DPadRight)]),
+            (Action::Move(Direction::Down),
+                vec![Key(KeyCode::Down), Key(KeyCode::S), Key(KeyCode::J), Button(DPadDown)]),
+            (Action::Undo, vec![Key(KeyCode::Z), Key(KeyCode::U), Button(East)]),
+            (Action::Redo, vec![Key(KeyCode::Y), Shift(KeyCode::Z), Button(West)]),
+            (Action::Restart, vec![Key(KeyCode::R), Button(RightTrigger)]),
+            (Action::Confirm, vec![Key(KeyCode::Return), Key(KeyCode::Space), Button(South)]),
+            (Action::Pause, vec![Key(KeyCode::Escape), Key(KeyCode::P), Button(Start)]),
+            // `/` and `?`, as H and L move the player
+            (Action::Hint, vec![Key(KeyCode::Slash), Button(North)]),
+            (Action::Solve, vec![Shift(KeyCode::Slash), Button(LeftTrigger)]),
+            (Action::LevelSelect, vec![Key(KeyCode::Tab), Button(Select)]),
+            (Action::Replay, vec![Key(KeyCode::V)]),
+            (Action::Fullscreen, vec![Key(KeyCode::F11), Alt(KeyCode::Return)]),
+            (Action::NewMap, vec![Key(KeyCode::N)]),
+            (Action::Export, vec![Key(KeyCode::X)]),
+        ]).into_iter()
+            // The number row, with 0 after 9 as on the keyboard
+            .chain([KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5,
+                KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9, KeyCode::Key0]
+                .into_iter().zip(0..).map(|(key, slot)| (Action::Brush(slot), vec![Key(key)])))
+            .collect())
+    }
+}
+
+impl Bindings {
+    // Actions whose inputs are held down. A plain key binding gives way to a
+    // shifted or alt one of the same key while that modifier is held.
+    pub fn active(&self, keys: &Input<KeyCode>, button_pressed: impl Fn(GamepadButtonType) -> bool)
+        -> HashSet<Action> {
+
+        let shift = keys.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+        let alt = keys.any_pressed([KeyCode::LAlt, KeyCode::RAlt]);
+        let modified = |key| self.0.values().flatten().any(|input|
+            (shift && *input == InputBinding::Shift(key)) || (alt && *input == InputBinding::Alt(key)));
+
+        self.0.iter()
+            .filter(|(_, inputs)| inputs.iter().any(|input| match *input {
+                InputBinding::Key(key) => keys.pressed(key) && !modified(key),
+                InputBinding::Shift(key) => shift && keys.pressed(key),
+                InputBinding::Alt(key) => alt && keys.pressed(key),
+                InputBinding::Button(button) => button_pressed(button),
+            }))
+            .map(|(action, _)| *action)
+            .collect()
+    }
+}
+
+// Direction the stick is tilted in, if it's tilted far enough
+pub fn stick_direction(x: f32, y: f32) -> Option<Direction> {
+    if x.abs().max(y.abs()) < STICK_THRESHOLD {
+        None
+    } else if x.abs() > y.abs() {
+        Some(if x < 0. { Direction::Left } else { Direction::Right })
+    } else {
+        Some(if y > 0. { Direction::Up } else { Direction::Down })
+    }
+}
+
+// The actions held down this frame and the one before
+#[derive(Resource, Default)]
+pub struct ActionState {
+    pressed: HashSet<Action>,
+    previous: HashSet<Action>,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action) && !self.previous.contains(&action)
+    }
+
+    // The move held down, with the same priority as the directions are listed in
+    pub fn move_pressed(&self) -> Option<Direction> {
+        Direction::ALL.into_iter().find(|direction| self.pressed(Action::Move(*direction)))
+    }
+
+    pub fn move_just_pressed(&self) -> Option<Direction> {
+        Direction::ALL.into_iter().find(|direction| self.just_pressed(Action::Move(*direction)))
+    }
+}
+
+pub fn update_actions(
+    keyboard_input: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    settings: Res<Settings>,
+    mut actions: ResMut<ActionState>) {
+
+    let button_pressed = |button_type| gamepads.iter().any(|gamepad|
+        gamepad_buttons.pressed(GamepadButton::new(gamepad, button_type)));
+    let mut pressed = settings.bindings.active(&keyboard_input, button_pressed);
+
+    for gamepad in gamepads.iter() {
+        let axis = |axis_type| gamepad_axes.get(GamepadAxis::new(gamepad, axis_type)).unwrap_or(0.);
+        let stick = stick_direction(
+            axis(GamepadAxisType::LeftStickX), axis(GamepadAxisType::LeftStickY));
+        if let Some(direction) = stick {
+            pressed.insert(Action::Move(direction));
+        }
+    }
+
+    actions.previous = std::mem::replace(&mut actions.pressed, pressed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active(keys: &[KeyCode]) -> HashSet<Action> {
+        let mut input = Input::default();
+        for key in keys {
+            input.press(*key);
+        }
+        Bindings::default().active(&input, |_| false)
+    }
+
+    #[test]
+    fn arrows_wasd_and_vi_keys_all_move() {
+        for key in [KeyCode::Left, KeyCode::A, KeyCode::H] {
+            assert_eq!(active(&[key]), HashSet::from([Action::Move(Direction::Left)]));
+        }
+    }
+
+    #[test]
+    fn shift_picks_the_shifted_binding() {
+        assert_eq!(active(&[KeyCode::Z]), HashSet::from([Action::Undo]));
+        assert_eq!(active(&[KeyCode::LShift, KeyCode::Z]), HashSet::from([Action::Redo]));
+        assert_eq!(active(&[KeyCode::RShift, KeyCode::Slash]), HashSet::from([Action::Solve]));
+        // Keys without a shifted binding work with shift held too
+        assert_eq!(active(&[KeyCode::LShift, KeyCode::Up]),
+            HashSet::from([Action::Move(Direction::Up)]));
+    }
+
+    #[test]
+    fn alt_enter_goes_fullscreen_instead_of_confirming() {
+        assert_eq!(active(&[KeyCode::Return]), HashSet::from([Action::Confirm]));
+        assert_eq!(active(&[KeyCode::LAlt, KeyCode::Return]), HashSet::from([Action::Fullscreen]));
+        assert_eq!(active(&[KeyCode::F11]), HashSet::from([Action::Fullscreen]));
+    }
+
+    #[test]
+    fn gamepad_buttons_trigger_actions() {
+        let bindings = Bindings::default();
+        let pressed = bindings.active(&Input::default(),
+            |button| button == GamepadButtonType::South);
+        assert_eq!(pressed, HashSet::from([Action::Confirm]));
+    }
+
+    #[test]
+    fn the_number_row_picks_editor_brushes() {
+        assert_eq!(active(&[KeyCode::Key1]), HashSet::from([Action::Brush(0)]));
+        assert_eq!(active(&[KeyCode::Key0]), HashSet::from([Action::Brush(9)]));
+    }
+
+    #[test]
+    fn older_settings_get_the_default_bindings_of_new_actions() {
+        let bindings: Bindings = ron::from_str("({Undo: [Key(Back)]})").unwrap();
+        assert_eq!(bindings.0[&Action::Undo], vec![InputBinding::Key(KeyCode::Back)]);
+        assert_eq!(bindings.0[&Action::Export], vec![InputBinding::Key(KeyCode::X)]);
+    }
+
+    #[test]
+    fn the_stick_needs_a_clear_tilt() {
+        assert_eq!(stick_direction(0.2, -0.3), None);
+        assert_eq!(stick_direction(-0.9, 0.4), Some(Direction::Left));
+        assert_eq!(stick_direction(0.3, 0.8), Some(Direction::Up));
+        assert_eq!(stick_direction(0.1, -0.6), Some(Direction::Down));
+    }
+
+    #[test]
+    fn just_pressed_only_lasts_a_frame() {
+        let mut actions = ActionState::default();
+        actions.pressed.insert(Action::Undo);
+        assert!(actions.just_pressed(Action::Undo));
+        actions.previous = actions.pressed.clone();
+        assert!(actions.pressed(Action::Undo));
+        assert!(!actions.just_pressed(Action::Undo));
+    }
+}
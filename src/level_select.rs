@@ -0,0 +1,325 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+use crate::hud::format_time;
+use crate::input::{Action, ActionState};
+use crate::replay::Replay;
+use crate::levels::{LevelPack, ParsedLevel, Tile};
+use crate::save::SaveData;
+use crate::{GameLevel, GameLevelPack, GameState, LevelPackPath};
+
+// Levels shown per page, in rows of `COLUMNS`
+const COLUMNS: usize = 4;
+const ROWS: usize = 2;
+// Largest size of a thumbnail on screen
+const THUMBNAIL_WIDTH: f32 = 24.;
+const THUMBNAIL_HEIGHT: f32 = 14.;
+
+// The level highlighted on the level select screen
+#[derive(Resource, Default)]
+pub struct LevelCursor(pub usize);
+
+// One image per level of the pack, one pixel per tile
+#[derive(Resource, Default)]
+pub struct LevelThumbnails(pub Vec<(Handle<Image>, Vec2)>);
+
+#[derive(Component)]
+pub struct LevelSelectScreen;
+
+const OUTSIDE: [u8; 4] = [0, 0, 0, 0];
+const FLOOR: [u8; 4] = [40, 36, 44, 255];
+const WALL: [u8; 4] = [120, 110, 100, 255];
+const DOOR: [u8; 4] = [200, 60, 50, 255];
+const SWITCH: [u8; 4] = [90, 80, 150, 255];
+const GATE: [u8; 4] = [140, 120, 220, 255];
+const LOCK: [u8; 4] = [230, 200, 60, 255];
+const KEY: [u8; 4] = [255, 230, 120, 255];
+const ICE: [u8; 4] = [150, 210, 250, 255];
+const ONE_WAY: [u8; 4] = [110, 90, 50, 255];
+const HOLE: [u8; 4] = [10, 8, 8, 255];
+const GOAL: [u8; 4] = [200, 170, 40, 255];
+const BOX: [u8; 4] = [150, 90, 40, 255];
+const BOX_ON_GOAL: [u8; 4] = [80, 180, 60, 255];
+const PLAYER: [u8; 4] = [80, 140, 230, 255];
+
+// RGBA pixels of a level, row by row
+pub fn thumbnail_pixels(level: &ParsedLevel) -> Vec<u8> {
+    level.tiles.iter().enumerate().flat_map(|(index, tile)| {
+        let on_goal = level.goals.contains(&index);
+        if index == level.player {
+            PLAYER
+        } else if level.boxes.contains(&index) {
+            if on_goal { BOX_ON_GOAL } else { BOX }
+        } else if on_goal {
+            GOAL
+        } else if level.keys.contains(&index) {
+            KEY
+        } else {
+            match tile {
+                Tile::Outside => OUTSIDE,
+                Tile::Floor => FLOOR,
+                Tile::Wall => WALL,
+                Tile::Door => DOOR,
+                Tile::Switch(_) => SWITCH,
+                Tile::Gate(_) => GATE,
+                Tile::Lock => LOCK,
+                Tile::Ice => ICE,
+                Tile::OneWay(_) => ONE_WAY,
+                Tile::Hole => HOLE,
+            }
+        }
+    }).collect()
+}
+
+fn thumbnail_image(level: &ParsedLevel) -> Image {
+    Image::new(
+        Extent3d {
+            width: level.size.width as u32,
+            height: level.size.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        thumbnail_pixels(level),
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+// Largest size that fits the thumbnail box while keeping the tiles square
+fn thumbnail_size(level: &ParsedLevel) -> Vec2 {
+    let (width, height) = (level.size.width as f32, level.size.height as f32);
+    let scale = (THUMBNAIL_WIDTH / width).min(THUMBNAIL_HEIGHT / height);
+    Vec2::new(width * scale, height * scale)
+}
+
+// Moves the cursor around the grid, staying within the pack
+fn move_cursor(cursor: usize, dx: i32, dy: i32, level_count: usize) -> usize {
+    let target = cursor as i32 + dx + dy * COLUMNS as i32;
+    if target < 0 || target >= level_count as i32 { cursor } else { target as usize }
+}
+
+pub fn open_level_select(
+    actions: Res<ActionState>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    if actions.just_pressed(Action::LevelSelect) {
+        next_state.set(GameState::LevelSelect);
+    }
+}
+
+pub fn enter_level_select(
+    mut commands: Commands,
+    game_level: Res<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>,
+    mut images: ResMut<Assets<Image>>) {
+
+    let thumbnails = level_packs.get(&level_pack.0).map(|pack| pack.levels.iter()
+        .map(|level| {
+            let parsed = level.parse().expect("levels are validated when the pack is loaded");
+            (images.add(thumbnail_image(&parsed)), thumbnail_size(&parsed))
+        }).collect()).unwrap_or_default();
+    commands.insert_resource(LevelThumbnails(thumbnails));
+    commands.insert_resource(LevelCursor(game_level.0));
+}
+
+pub fn exit_level_select(
+    mut commands: Commands,
+    screen_query: Query<Entity, With<LevelSelectScreen>>) {
+
+    for entity in &screen_query {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<LevelThumbnails>();
+    commands.remove_resource::<LevelCursor>();
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn navigate_level_select(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    thumbnails: Res<LevelThumbnails>,
+    save_data: Res<SaveData>,
+    level_pack_path: Res<LevelPackPath>,
+    mut cursor: ResMut<LevelCursor>,
+    mut game_level: ResMut<GameLevel>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    let level_count = thumbnails.0.len();
+    if let Some(direction) = actions.move_just_pressed() {
+        let (dx, dy) = direction.delta();
+        cursor.0 = move_cursor(cursor.0, dx, dy, level_count);
+    }
+
+    if actions.just_pressed(Action::Confirm) {
+        let unlocked = save_data.pack(&level_pack_path.0).map_or(0, |progress| progress.unlocked);
+        if cursor.0 <= unlocked {
+            game_level.0 = cursor.0;
+            next_state.set(GameState::Starting);
+        }
+    } else if actions.just_pressed(Action::Replay) {
+        // Watch the best solution found so far
+        let solution = save_data.pack(&level_pack_path.0)
+            .and_then(|progress| progress.best.get(&cursor.0))
+            .map(|best| best.solution.as_str())
+            .filter(|solution| !solution.is_empty());
+        if let Some(Ok(replay)) = solution.map(Replay::new) {
+            commands.insert_resource(replay);
+            game_level.0 = cursor.0;
+            next_state.set(GameState::Starting);
+        }
+    } else if actions.just_pressed(Action::Pause) {
+        next_state.set(GameState::MainMenu);
+    }
+}
+
+// Rebuilds the screen whenever the cursor moves, the page may have changed
+pub fn draw_level_select(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    cursor: Res<LevelCursor>,
+    thumbnails: Res<LevelThumbnails>,
+    save_data: Res<SaveData>,
+    level_pack_path: Res<LevelPackPath>,
+    screen_query: Query<Entity, With<LevelSelectScreen>>) {
+
+    if !cursor.is_changed() {
+        return;
+    }
+    for entity in &screen_query {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let progress = save_data.pack(&level_pack_path.0).cloned().unwrap_or_default();
+    let font = asset_server.load("Minimal3x5.ttf");
+    let text_style = |color| TextStyle { font: font.clone(), font_size: 5.0, color };
+
+    let page_size = COLUMNS * ROWS;
+    let page = cursor.0 / page_size;
+    let page_count = thumbnails.0.len().div_ceil(page_size);
+
+    let footer = if cursor.0 > progress.unlocked {
+        String::from("Locked")
+    } else if let Some(best) = progress.best.get(&cursor.0) {
+        format!("Best {} moves {} pushes {}", best.moves, best.pushes, format_time(best.time))
+    } else {
+        String::from("Not solved yet")
+    };
+
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::SpaceEvenly,
+                ..default()
+            },
+            ..default()
+        },
+        LevelSelectScreen,
+    )).with_children(|screen| {
+        screen.spawn(TextBundle::from_section(
+            format!("Select a level  {}/{}", page + 1, page_count), text_style(Color::WHITE)));
+
+        screen.spawn(NodeBundle {
+            style: Style {
+                size: Size::width(Val::Px(COLUMNS as f32 * (THUMBNAIL_WIDTH + 6.))),
+                flex_wrap: FlexWrap::Wrap,
+                ..default()
+            },
+            ..default()
+        }).with_children(|grid| {
+            for (index, (image, size)) in thumbnails.0.iter().enumerate()
+                .skip(page * page_size).take(page_size) {
+
+                let locked = index > progress.unlocked;
+                let label_color = if locked {
+                    Color::DARK_GRAY
+                } else if progress.best.contains_key(&index) {
+                    Color::LIME_GREEN
+                } else {
+                    Color::WHITE
+                };
+
+                grid.spawn(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(THUMBNAIL_WIDTH + 6.), Val::Px(THUMBNAIL_HEIGHT + 10.)),
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        justify_content: JustifyContent::Center,
+                        ..default()
+                    },
+                    ..default()
+                }).with_children(|cell| {
+                    // Frame around the thumbnail, highlighted under the cursor
+                    cell.spawn(NodeBundle {
+                        style: Style {
+                            padding: UiRect::all(Val::Px(1.)),
+                            ..default()
+                        },
+                        background_color: if index == cursor.0 {
+                            Color::YELLOW.into()
+                        } else {
+                            Color::NONE.into()
+                        },
+                        ..default()
+                    }).with_children(|frame| {
+                        frame.spawn(ImageBundle {
+                            style: Style {
+                                size: Size::new(Val::Px(size.x), Val::Px(size.y)),
+                                ..default()
+                            },
+                            image: UiImage::new(image.clone()),
+                            // Locked levels are dimmed
+                            background_color: if locked {
+                                Color::rgb(0.3, 0.3, 0.3).into()
+                            } else {
+                                Color::WHITE.into()
+                            },
+                            ..default()
+                        });
+                    });
+                    let label = if locked { format!("{} x", index + 1) } else { format!("{}", index + 1) };
+                    cell.spawn(TextBundle::from_section(label, text_style(label_color)));
+                });
+            }
+        });
+
+        screen.spawn(TextBundle::from_section(footer, text_style(Color::GRAY)));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::levels::parse_level;
+
+    #[test]
+    fn thumbnails_have_a_pixel_per_tile() {
+        let level = parse_level("#####\n#@$.#\n##*##\n # #\n ###").unwrap();
+        let pixels = thumbnail_pixels(&level);
+        assert_eq!(pixels.len(), 5 * 5 * 4);
+        let pixel = |x: usize, y: usize| &pixels[(x + y * 5) * 4..][..4];
+        assert_eq!(pixel(0, 0), WALL);
+        assert_eq!(pixel(1, 1), PLAYER);
+        assert_eq!(pixel(2, 1), BOX);
+        assert_eq!(pixel(3, 1), GOAL);
+        assert_eq!(pixel(2, 2), BOX_ON_GOAL);
+        assert_eq!(pixel(0, 3), OUTSIDE);
+    }
+
+    #[test]
+    fn thumbnails_keep_the_level_proportions() {
+        let level = parse_level("########\n#@$   .#\n########").unwrap();
+        assert_eq!(thumbnail_size(&level), Vec2::new(24., 9.));
+    }
+
+    #[test]
+    fn the_cursor_stays_within_the_pack() {
+        assert_eq!(move_cursor(0, -1, 0, 10), 0);
+        assert_eq!(move_cursor(0, 1, 0, 10), 1);
+        assert_eq!(move_cursor(1, 0, 1, 10), 1 + COLUMNS);
+        assert_eq!(move_cursor(8, 0, 1, 10), 8);
+        assert_eq!(move_cursor(9, 1, 0, 10), 9);
+    }
+}
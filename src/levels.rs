@@ -1,57 +1,590 @@
-pub const LEVELS: [&str; 5] = [
-// Level 1
-r"
-########
-#@.....#
-#..bo..#
-#.b.o..#
-#......D
-########
-",
-
-// Level 2
-r"
-########
-#o..@..#
-#ob....#
-#b.....#
-#......#
-####D###
-",
-
-// Level 3
-r"
-#######D###
-#.........#
-#...bb....#
-#..obo.o..#
-#.....@...#
-###########
-",
-
-// Level 4
-r"
-###########
-#....#....#
-#..b.obb..#
-#..b.#....#
-#..o###o.@#
-#....#....#
-D....o....#
-#....#....#
-###########
-",
-
-// Level 5
-r"
-#############
-#...#.......#
-#...o#.#o#..#
-#....b.b.#..#
-#.o#b.@.b#o.#
-#..#..b.....#
-#..#..o..#..#
-#...........#
-######D######
-"
-];
+use std::fmt;
+use std::path::Path;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+
+use crate::components::{Direction, MapSize};
+
+// Characters that may appear on a board row in the XSB format. Our own
+// additions, otherwise unused by the standard: `D` for exit doors, `a`-`c` for
+// switches that open the gates `A`-`C`, `k` for keys that open locked doors
+// `K`, `~` for ice, `<>^v` for one-way floors and `x` for holes.
+const MAP_CHARS: &str = "#@+$*. -_DabcABCkK~<>^vx";
+// Keys of the `Key: value` lines a pack file describes its levels with
+const METADATA_KEYS: [&str; 4] = ["title", "author", "music", "comment"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Level {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    // Background music from this level on, relative to the assets folder
+    pub music: Option<String>,
+    pub map: String,
+    // Line of the first map row in the level pack, starting at 1
+    pub line: usize,
+}
+
+impl Level {
+    // Same as `parse_level`, with error positions relative to the whole pack
+    pub fn parse(&self) -> Result<ParsedLevel, LevelError> {
+        parse_level(&self.map).map_err(|mut err| {
+            err.line += self.line.saturating_sub(1);
+            err
+        })
+    }
+
+    pub fn name(&self, index: usize) -> String {
+        self.title.clone().unwrap_or_else(|| format!("Level {}", index + 1))
+    }
+}
+
+#[derive(Debug, TypeUuid)]
+#[uuid = "5d3c8f0e-2b7a-4c61-9e4f-8a1d6b2c7e90"]
+pub struct LevelPack {
+    pub levels: Vec<Level>,
+}
+
+impl LevelPack {
+    // The music of the closest level up to this one that names any
+    pub fn music(&self, level: usize) -> Option<&str> {
+        self.levels.iter().take(level + 1).rev().find_map(|level| level.music.as_deref())
+    }
+}
+
+#[derive(Default)]
+pub struct LevelPackLoader;
+
+impl AssetLoader for LevelPackLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let levels = parse_levels(std::str::from_utf8(bytes)?);
+            if levels.is_empty() {
+                return Err(bevy::asset::Error::msg("no levels found in level pack"));
+            }
+            for (index, level) in levels.iter().enumerate() {
+                if let Err(err) = level.parse() {
+                    return Err(bevy::asset::Error::msg(
+                        format!("{}: {err}", level.name(index))));
+                }
+            }
+            load_context.set_default_asset(LoadedAsset::new(LevelPack { levels }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["xsb", "sok"]
+    }
+}
+
+// Rows that start with a wall are kept even with unknown characters in them,
+// so the level parser can point at the offending character
+fn is_map_row(line: &str) -> bool {
+    line.contains('#') && (line.chars().all(|c| MAP_CHARS.contains(c))
+        || line.trim_start_matches([' ', '-', '_']).starts_with('#'))
+}
+
+/// Parses every level of a standard Sokoban collection (.xsb / .sok / .txt).
+///
+/// Boards are runs of consecutive map rows. `Title:`, `Author:` and `Music:`
+/// lines after a board belong to it, while a plain text line before a board is
+/// taken as its title unless a `Title:` says otherwise. Lines starting with `;`
+/// and `Comment:` blocks are skipped.
+pub fn parse_levels(source: &str) -> Vec<Level> {
+    let mut levels: Vec<Level> = Vec::new();
+    let mut pending = Level::default();
+    let mut rows: Vec<&str> = Vec::new();
+    let mut first_row = 0;
+    let mut attach_to_last = false;
+    let mut in_comment = false;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if in_comment {
+            in_comment = !trimmed.eq_ignore_ascii_case("comment-end:")
+                && !trimmed.eq_ignore_ascii_case("comment_end:");
+            continue;
+        }
+
+        if is_map_row(line) {
+            if rows.is_empty() {
+                first_row = line_index + 1;
+            }
+            rows.push(line.trim_end());
+            continue;
+        }
+
+        if !rows.is_empty() {
+            let mut level = std::mem::take(&mut pending);
+            level.map = rows.join("\n");
+            level.line = first_row;
+            levels.push(level);
+            rows.clear();
+            attach_to_last = true;
+        }
+
+        if trimmed.is_empty() || trimmed.starts_with(';') {
+            continue;
+        }
+
+        // Any other line with a colon in it, like "Level 1: Easy", is a bare title
+        let metadata = trimmed.split_once(':')
+            .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim()))
+            .filter(|(key, _)| METADATA_KEYS.contains(&key.as_str()));
+        if let Some((key, value)) = metadata {
+            let target = match levels.last_mut() {
+                Some(last) if attach_to_last => last,
+                _ => &mut pending,
+            };
+            match key.as_str() {
+                "title" => target.title = Some(value.to_string()),
+                "author" => target.author = Some(value.to_string()),
+                "music" => target.music = Some(value.to_string()),
+                "comment" if value.is_empty() => in_comment = true,
+                _ => {}
+            }
+        } else {
+            // A bare line names the board that follows it
+            attach_to_last = false;
+            if pending.title.is_none() {
+                pending.title = Some(trimmed.to_string());
+            }
+        }
+    }
+
+    if !rows.is_empty() {
+        pending.map = rows.join("\n");
+        pending.line = first_row;
+        levels.push(pending);
+    }
+
+    levels
+}
+
+// Reads a pack file and parses every level of it, reporting what's wrong
+// under `name`. Levels that parse are handed to `check`, which reports on them
+// and tells whether they passed. Returns whether everything did.
+pub fn check_level_pack(
+    path: &Path,
+    name: &str,
+    mut check: impl FnMut(&[Level], usize, ParsedLevel) -> bool,
+) -> bool {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{}: {err}", path.display());
+            return false;
+        }
+    };
+    let levels = parse_levels(&source);
+    if levels.is_empty() {
+        eprintln!("{name}: no levels found");
+        return false;
+    }
+
+    let mut valid = true;
+    for (index, level) in levels.iter().enumerate() {
+        match level.parse() {
+            Ok(parsed) => valid &= check(&levels, index, parsed),
+            Err(err) => {
+                eprintln!("{name}:{err} ({})", level.name(index));
+                valid = false;
+            }
+        }
+    }
+    valid
+}
+
+// Validates every level of the given pack files, printing a line per level.
+// Returns whether all of them are valid.
+pub fn check_level_files(paths: &[String]) -> bool {
+    let mut valid = true;
+    for path in paths {
+        valid &= check_level_pack(Path::new(path), path, |levels, index, _| {
+            println!("{path}: {} ok", levels[index].name(index));
+            true
+        });
+    }
+    valid
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tile {
+    // Empty space around the walls of the level
+    Outside,
+    Floor,
+    Wall,
+    // Exits, which open once every goal has a box
+    Door,
+    // Floor switches and the gates they hold open, by letter from 0
+    Switch(u8),
+    Gate(u8),
+    // Locked door, opened for good with a key
+    Lock,
+    // Boxes and the player slide over ice until something stops them
+    Ice,
+    // Floor that can only be entered going the given way
+    OneWay(Direction),
+    // Only boxes go in, and the first one fills it up to floor
+    Hole,
+}
+
+// A level that passed validation, ready to be spawned
+#[derive(Debug, Clone)]
+pub struct ParsedLevel {
+    pub size: MapSize,
+    pub tiles: Vec<Tile>,
+    pub player: usize,
+    pub boxes: Vec<usize>,
+    pub goals: Vec<usize>,
+    pub doors: Vec<usize>,
+    // Keys lying on the floor
+    pub keys: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LevelErrorKind {
+    UnknownCharacter(char),
+    // A short row leaves the floor open to the outside
+    NonRectangularRow,
+    // The floor runs into the edge of the map without a wall
+    OpenBoundary,
+    MissingPlayer,
+    MultiplePlayers,
+    BoxGoalMismatch { boxes: usize, goals: usize },
+    // Without goals or a door, there's nothing to do and the level is won
+    // straight away
+    NothingToSolve,
+    // The door is not on the outer wall or can't be reached by the player
+    UnreachableDoor,
+    // A gate that no switch opens
+    GateWithoutSwitch(char),
+    NotEnoughKeys { keys: usize, locks: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelError {
+    pub kind: LevelErrorKind,
+    // Both start at 1
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for LevelErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownCharacter(c) => write!(f, "unknown character '{c}'"),
+            Self::NonRectangularRow => write!(f, "row is too short and leaves the level open"),
+            Self::OpenBoundary => write!(f, "floor reaches the edge of the map without a wall"),
+            Self::MissingPlayer => write!(f, "level has no player"),
+            Self::MultiplePlayers => write!(f, "level has more than one player"),
+            Self::BoxGoalMismatch { boxes, goals } =>
+                write!(f, "level has {boxes} boxes but {goals} goals"),
+            Self::NothingToSolve => write!(f, "level has neither goals nor a door"),
+            Self::UnreachableDoor => write!(f, "door can't be reached from inside the level"),
+            Self::GateWithoutSwitch(gate) => write!(f, "gate '{gate}' has no switch"),
+            Self::NotEnoughKeys { keys, locks } =>
+                write!(f, "level has {keys} keys but {locks} locked doors"),
+        }
+    }
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for LevelError {}
+
+/// Parses and validates a single level in the XSB alphabet.
+///
+/// Rows may be ragged, as long as the missing part lies outside the walls.
+pub fn parse_level(map: &str) -> Result<ParsedLevel, LevelError> {
+    let rows: Vec<Vec<char>> = map.lines().map(|row| row.chars().collect()).collect();
+    let size = MapSize {
+        width: rows.iter().map(|row| row.len()).max().unwrap_or(0) as i32,
+        height: rows.len() as i32,
+    };
+    let error = |kind, index: usize| LevelError {
+        kind,
+        line: index / size.width.max(1) as usize + 1,
+        column: index % size.width.max(1) as usize + 1,
+    };
+
+    let mut tiles = vec![Tile::Outside; (size.width * size.height) as usize];
+    // Tiles past the end of a short row
+    let mut padding = vec![false; tiles.len()];
+    let mut player = None;
+    let mut boxes = Vec::new();
+    let mut goals = Vec::new();
+    let mut doors = Vec::new();
+    let mut keys = Vec::new();
+
+    for (y, row) in rows.iter().enumerate() {
+        for x in 0..size.width as usize {
+            let index = x + y * size.width as usize;
+            let Some(&c) = row.get(x) else {
+                padding[index] = true;
+                continue;
+            };
+
+            tiles[index] = match c {
+                '#' => Tile::Wall,
+                'D' => Tile::Door,
+                'K' => Tile::Lock,
+                'a'..='c' => Tile::Switch(c as u8 - b'a'),
+                'A'..='C' => Tile::Gate(c as u8 - b'A'),
+                '~' => Tile::Ice,
+                '<' => Tile::OneWay(Direction::Left),
+                '>' => Tile::OneWay(Direction::Right),
+                '^' => Tile::OneWay(Direction::Up),
+                'v' => Tile::OneWay(Direction::Down),
+                'x' => Tile::Hole,
+                ' ' | '-' | '_' | '@' | '+' | '$' | '*' | '.' | 'k' => Tile::Floor,
+                _ => return Err(error(LevelErrorKind::UnknownCharacter(c), index)),
+            };
+
+            if matches!(c, '@' | '+') && player.replace(index).is_some() {
+                return Err(error(LevelErrorKind::MultiplePlayers, index));
+            }
+            if matches!(c, '$' | '*') {
+                boxes.push(index);
+            }
+            if matches!(c, '.' | '+' | '*') {
+                goals.push(index);
+            }
+            if c == 'D' {
+                doors.push(index);
+            }
+            if c == 'k' {
+                keys.push(index);
+            }
+        }
+    }
+
+    let Some(player) = player else {
+        // Points at the start of the first row, as there's nothing better
+        let first = rows.first().and_then(|row| row.iter().position(|c| *c != ' ')).unwrap_or(0);
+        return Err(error(LevelErrorKind::MissingPlayer, first));
+    };
+
+    // Every goal needs a box, and spare boxes need a hole to go in. Points at
+    // the first goal left without a box or the first box too many.
+    let holes = tiles.iter().filter(|tile| **tile == Tile::Hole).count();
+    if boxes.len() < goals.len() || boxes.len() > goals.len() + holes {
+        let kind = LevelErrorKind::BoxGoalMismatch { boxes: boxes.len(), goals: goals.len() };
+        let culprit = if boxes.len() < goals.len() {
+            goals.iter().find(|goal| !boxes.contains(goal))
+        } else {
+            boxes.iter().find(|box_index| !goals.contains(box_index))
+        };
+        return Err(error(kind, culprit.copied().unwrap_or(0)));
+    }
+    if goals.is_empty() && doors.is_empty() {
+        return Err(error(LevelErrorKind::NothingToSolve, player));
+    }
+
+    let neighbours = |index: usize| {
+        let (x, y) = ((index % size.width as usize) as i32, (index / size.width as usize) as i32);
+        [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)].into_iter().map(move |(x, y)| {
+            (x >= 0 && x < size.width && y >= 0 && y < size.height)
+                .then(|| (x + y * size.width) as usize)
+        })
+    };
+
+    // Everything the player can walk to, ignoring boxes, must be closed off by walls
+    let mut inside = vec![false; tiles.len()];
+    let mut stack = vec![player];
+    inside[player] = true;
+    while let Some(index) = stack.pop() {
+        for neighbour in neighbours(index) {
+            let Some(neighbour) = neighbour else {
+                return Err(error(LevelErrorKind::OpenBoundary, index));
+            };
+            if padding[neighbour] {
+                return Err(error(LevelErrorKind::NonRectangularRow, neighbour));
+            }
+            let passable = matches!(tiles[neighbour], Tile::Floor | Tile::Switch(_) | Tile::Gate(_)
+                | Tile::Lock | Tile::Ice | Tile::OneWay(_) | Tile::Hole);
+            if passable && !inside[neighbour] {
+                inside[neighbour] = true;
+                stack.push(neighbour);
+            }
+        }
+    }
+
+    // Floor reachable from the edge of the map without crossing a wall
+    let is_open = |index: usize| padding[index] || (tiles[index] == Tile::Floor && !inside[index]);
+    let mut outside = vec![false; tiles.len()];
+    let mut stack: Vec<usize> = (0..tiles.len())
+        .filter(|&index| neighbours(index).any(|neighbour| neighbour.is_none()))
+        .collect();
+    while let Some(index) = stack.pop() {
+        if outside[index] || !is_open(index) {
+            continue;
+        }
+        outside[index] = true;
+        stack.extend(neighbours(index).flatten());
+    }
+
+    for &door in &doors {
+        let on_boundary = neighbours(door)
+            .any(|neighbour| neighbour.is_none_or(|n| outside[n]));
+        let reachable = neighbours(door).any(|neighbour| neighbour.is_some_and(|n| inside[n]));
+        if !on_boundary || !reachable {
+            return Err(error(LevelErrorKind::UnreachableDoor, door));
+        }
+    }
+
+    for (index, tile) in tiles.iter().enumerate() {
+        if let Tile::Gate(letter) = *tile {
+            if !tiles.contains(&Tile::Switch(letter)) {
+                return Err(error(LevelErrorKind::GateWithoutSwitch((b'A' + letter) as char), index));
+            }
+        }
+    }
+    let locks: Vec<usize> = (0..tiles.len()).filter(|&index| tiles[index] == Tile::Lock).collect();
+    if keys.len() < locks.len() {
+        let kind = LevelErrorKind::NotEnoughKeys { keys: keys.len(), locks: locks.len() };
+        return Err(error(kind, locks[0]));
+    }
+
+    for (index, tile) in tiles.iter_mut().enumerate() {
+        if outside[index] && !boxes.contains(&index) && !goals.contains(&index) && !keys.contains(&index) {
+            *tile = Tile::Outside;
+        }
+    }
+
+    Ok(ParsedLevel { size, tiles, player, boxes, goals, doors, keys })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_rows_are_told_apart_from_text() {
+        assert!(is_map_row("#####"));
+        assert!(is_map_row("  #@$.#"));
+        assert!(is_map_row("--#_.#"));
+        // Kept so the level parser can point at the unknown character
+        assert!(is_map_row("#@?#"));
+        assert!(!is_map_row("Title: #1"));
+        assert!(!is_map_row("Level one"));
+        assert!(!is_map_row("    "));
+    }
+
+    #[test]
+    fn titles_and_authors_go_with_their_boards() {
+        let levels = parse_levels(concat!(
+            "First\n\n#####\n#@$.#\n#####\nAuthor: Someone\n\n",
+            "#####\n#@$.#\n#####\nTitle: Second\n\n",
+            // A key line names the board before it, over a bare title
+            "Ignored\n#####\n#@$.#\n#####\nTitle: Third\n"));
+        let names: Vec<_> = levels.iter().map(|level| level.title.as_deref()).collect();
+        assert_eq!(names, [Some("First"), Some("Second"), Some("Third")]);
+        assert_eq!(levels[0].author.as_deref(), Some("Someone"));
+        assert_eq!(levels[1].author, None);
+    }
+
+    #[test]
+    fn titles_can_have_colons_in_them() {
+        let levels = parse_levels("Level 1: Easy\n#####\n#@$.#\n#####\nAuthor: Someone\n");
+        assert_eq!(levels[0].title.as_deref(), Some("Level 1: Easy"));
+        assert_eq!(levels[0].author.as_deref(), Some("Someone"));
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let levels = parse_levels(concat!(
+            "; #####\n",
+            "Comment:\n#####\nNot a title\nComment-End:\n",
+            "#####\n#@$.#\n#####\n",
+            "comment:\n#####\ncomment_end:\n"));
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels[0].title, None);
+        assert_eq!(levels[0].map, "#####\n#@$.#\n#####");
+        assert_eq!(levels[0].line, 6);
+    }
+
+    #[test]
+    fn boards_are_split_on_anything_but_map_rows() {
+        // The last board ends the source without a blank line after it
+        let levels = parse_levels("#####\n#@$.#\n#####\n\n\n####\n#@*#\n####\n; between\n####  \n#@*#\n####");
+        let maps: Vec<_> = levels.iter().map(|level| (level.map.as_str(), level.line)).collect();
+        assert_eq!(maps, [("#####\n#@$.#\n#####", 1), ("####\n#@*#\n####", 6), ("####\n#@*#\n####", 10)]);
+    }
+
+    // Kind, line and column of the error a map gives
+    fn error_at(map: &str) -> (LevelErrorKind, usize, usize) {
+        let err = parse_level(map).unwrap_err();
+        (err.kind, err.line, err.column)
+    }
+
+    #[test]
+    fn unknown_characters_are_pointed_at() {
+        assert_eq!(error_at("#####\n#@$.#\n##?##"), (LevelErrorKind::UnknownCharacter('?'), 3, 3));
+    }
+
+    #[test]
+    fn short_rows_that_leave_the_level_open_are_refused() {
+        assert_eq!(error_at("#####\n#@$.#\n#   \n#####"), (LevelErrorKind::NonRectangularRow, 3, 5));
+    }
+
+    #[test]
+    fn floor_on_the_edge_of_the_map_is_refused() {
+        assert_eq!(error_at("#####\n@$. #\n#####"), (LevelErrorKind::OpenBoundary, 2, 1));
+    }
+
+    #[test]
+    fn a_missing_player_points_at_the_first_row() {
+        assert_eq!(error_at("  #####\n  #$. #\n  #####"), (LevelErrorKind::MissingPlayer, 1, 3));
+    }
+
+    #[test]
+    fn a_second_player_is_pointed_at() {
+        assert_eq!(error_at("######\n#@$.@#\n######"), (LevelErrorKind::MultiplePlayers, 2, 5));
+    }
+
+    #[test]
+    fn box_goal_mismatches_point_at_the_odd_one_out() {
+        // Neither goal has the box on it, the first one is pointed at
+        assert_eq!(error_at("######\n#@$..#\n######"),
+            (LevelErrorKind::BoxGoalMismatch { boxes: 1, goals: 2 }, 2, 4));
+        // The second box has no goal nor a hole to go in
+        assert_eq!(error_at("######\n#@*$ #\n######"),
+            (LevelErrorKind::BoxGoalMismatch { boxes: 2, goals: 1 }, 2, 4));
+    }
+
+    #[test]
+    fn levels_need_goals_or_a_door() {
+        assert_eq!(error_at("#####\n# @ #\n#####"), (LevelErrorKind::NothingToSolve, 2, 3));
+        assert!(parse_level("#####\n# @ D\n#####").is_ok());
+    }
+
+    #[test]
+    fn doors_must_be_reachable_on_the_outer_wall() {
+        assert_eq!(error_at("######\n#@$.D#\n######"), (LevelErrorKind::UnreachableDoor, 2, 5));
+    }
+
+    #[test]
+    fn gates_need_a_switch() {
+        assert_eq!(error_at("######\n#@$.A#\n######"), (LevelErrorKind::GateWithoutSwitch('A'), 2, 5));
+    }
+
+    #[test]
+    fn locked_doors_need_enough_keys() {
+        assert_eq!(error_at("#######\n#@$.KK#\n#k   ##\n#######"),
+            (LevelErrorKind::NotEnoughKeys { keys: 1, locks: 2 }, 2, 5));
+    }
+}
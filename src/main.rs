@@ -1,33 +1,81 @@
-use bevy::{prelude::*, window::WindowResolution};
+use bevy::{input::InputSystem, prelude::*, window::{WindowMode, WindowResolution}};
 // use bevy_inspector_egui::quick::WorldInspectorPlugin;
-pub mod components;
+pub mod audio;
+pub mod board;
+pub mod camera;
+mod components;
+pub mod deadlock;
+pub mod editor;
+pub mod headless;
+pub mod hint;
+pub mod history;
+pub mod hud;
+pub mod input;
+pub mod level_select;
 pub mod levels;
 pub mod map;
+pub mod menu;
+pub mod mouse;
+pub mod save;
+pub mod settings;
 pub mod player;
+pub mod replay;
+pub mod solver;
+pub mod tween;
 
+use audio::*;
+use camera::{fit_to_window, frame_map, integer_scale, remember_window_size, spawn_cameras, toggle_fullscreen};
+use deadlock::*;
+use editor::*;
+use headless::verify_level_pack;
+use hint::*;
+use history::*;
+use hud::*;
+use input::{update_actions, Action, ActionState};
+use level_select::*;
+use levels::{check_level_files, LevelPack, LevelPackLoader};
 use map::*;
+use menu::*;
+use mouse::{click_to_move, expire_drag_notice, DragStart};
 use player::*;
+use replay::*;
+use save::{load_ron, save_progress, save_ron, SaveData, SavePath};
+use settings::{save_settings, Settings, SettingsPath};
+use tween::{animate_tweens, start_tweens};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Default, States)]
 pub enum GameState {
     #[default]
+    Loading,
+    MainMenu,
     Starting,
     Playing,
+    Paused,
     Resetting,
     NextLevel,
-    GameOver
+    GameOver,
+    LevelSelect,
+    Editor,
 }
 
 #[derive(Resource)]
 pub struct GameLevel(usize);
 
 // Manually implement default to control the start level
+#[allow(clippy::derivable_impls)]
 impl Default for GameLevel {
     fn default() -> Self {
         Self(0)
     }
 }
 
+// Path of the level pack, relative to the assets folder
+#[derive(Resource)]
+pub struct LevelPackPath(pub String);
+
+#[derive(Resource)]
+pub struct GameLevelPack(pub Handle<LevelPack>);
+
 
 pub fn load_asset_atlas(
     asset_server: &Res<AssetServer>,  
@@ -39,20 +87,10 @@ pub fn load_asset_atlas(
     let asset_atlas =
         TextureAtlas::from_grid(asset_handle, 
         Vec2::new(8., 8.), columns, rows, padding, offset);
-    return texture_atlases.add(asset_atlas);
+    texture_atlases.add(asset_atlas)
 }
 
 
-fn spawn_camera(mut commands: Commands) {
-    commands.spawn(Camera2dBundle { 
-        transform: Transform {
-            translation: Vec3::new(0., 0., 10.),
-            ..default()
-        },
-        ..default()
-    });
-}
-
 fn spawn_gameover(
     mut commands: Commands,
     asset_server: Res<AssetServer>,  
@@ -60,7 +98,7 @@ fn spawn_gameover(
     commands.spawn(
         TextBundle::from_section(
             // Accepts a `String` or any type that converts into a `String`, such as `&str`
-            "Congratulations!\nYou've completed all the levels.\nPress 'Space' to start over.",
+            "Congratulations!\nYou've completed all the levels.\nPress 'Space' to start over\nor 'Tab' to pick a level.",
             TextStyle {
                 font: asset_server.load("Minimal3x5.ttf"),
                 font_size: 5.0,
@@ -87,22 +125,78 @@ fn clear_gameover(mut commands: Commands,
 fn startover(
     mut game_level: ResMut<GameLevel>,
     mut next_state: ResMut<NextState<GameState>>, 
-    keyboard_input: Res<Input<KeyCode>>,
+    actions: Res<ActionState>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::Space) {
+    if actions.just_pressed(Action::Confirm) {
         game_level.0 = 0;
         next_state.set(GameState::Starting);
+    } else if actions.just_pressed(Action::LevelSelect) {
+        next_state.set(GameState::LevelSelect);
     }
 }
 
 fn main() {
-    App::new()
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    // `cargo run -- --check <files>` validates level packs without starting the game
+    if args.first().map(String::as_str) == Some("--check") {
+        std::process::exit(if check_level_files(&args[1..]) { 0 } else { 1 });
+    }
+
+    // `cargo run -- --headless [pack]` plays every level of a pack without a window
+    if args.first().map(String::as_str) == Some("--headless") {
+        let pack_path = args.get(1).map_or("levels/default.xsb", String::as_str);
+        std::process::exit(if verify_level_pack(pack_path) { 0 } else { 1 });
+    }
+
+    // `--replay <file>` plays a LURD solution on the level the game starts on
+    let replay = args.iter().position(|arg| arg == "--replay").map(|index| {
+        let removed: Vec<String> = args.drain(index..(index + 2).min(args.len())).collect();
+        let Some(path) = removed.get(1) else {
+            eprintln!("--replay needs a file with a solution in LURD notation");
+            std::process::exit(1);
+        };
+        match std::fs::read_to_string(path).map_err(|err| err.to_string())
+            .and_then(|lurd| Replay::new(&lurd).map_err(|err| err.to_string())) {
+            Ok(replay) => replay,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            },
+        }
+    });
+
+    // An optional level collection in the standard XSB format, e.g. `cargo run -- levels/mine.xsb`
+    let level_pack_path = args.first().cloned()
+        .unwrap_or_else(|| String::from("levels/default.xsb"));
+
+    // Pick up from the first level that hasn't been solved yet
+    let save_path = SavePath::from_env();
+    let save_data = save_path.0.as_deref().map(SaveData::load).unwrap_or_default();
+    let start_level = save_data.pack(&level_pack_path).map_or(0, |progress| progress.unlocked);
+
+    // Key bindings and preferences, written out on the first run so they can be edited
+    let settings_path = SettingsPath::from_env();
+    let settings: Settings = settings_path.0.as_deref().map(load_ron).unwrap_or_default();
+    if let Some(path) = settings_path.0.as_deref().filter(|path| !path.exists()) {
+        if let Err(err) = save_ron(&settings, path) {
+            eprintln!("Couldn't write default settings to {}: {}", path.display(), err);
+        }
+    }
+
+    let window_size = UVec2::new(settings.window_size.0, settings.window_size.1);
+
+    let mut app = App::new();
+    app
         .add_plugins(
             DefaultPlugins
                 .set(WindowPlugin {
                     primary_window: Some(Window {
                         title: String::from("Sokoban"),
-                        resolution: WindowResolution::new(640., 400.).with_scale_factor_override(5.),
+                        // `fit_to_window` keeps the scale right as the window is resized
+                        resolution: WindowResolution::new(window_size.x as f32, window_size.y as f32)
+                            .with_scale_factor_override(integer_scale(window_size) as f64),
+                        mode: if settings.fullscreen { WindowMode::BorderlessFullscreen } else { WindowMode::Windowed },
                         ..Default::default()
                     }),
                     ..default()
@@ -114,23 +208,104 @@ fn main() {
                 })
         )
         .add_state::<GameState>()
-        .init_resource::<GameLevel>()
-        .add_startup_system(spawn_camera)
+        .insert_resource(GameLevel(start_level))
+        .insert_resource(LevelPackPath(level_pack_path))
+        .insert_resource(save_path)
+        .insert_resource(save_data)
+        .add_asset::<LevelPack>()
+        .init_asset_loader::<LevelPackLoader>()
+        .init_resource::<MoveHistory>()
+        .init_resource::<Autoplay>()
+        .init_resource::<Deadlock>()
+        .init_resource::<LevelStats>()
+        .insert_resource(settings)
+        .insert_resource(settings_path)
+        .init_resource::<ActionState>()
+        .init_resource::<DragStart>()
+        .add_event::<Deadlocked>()
+        .add_event::<PlaySound>()
+        .init_resource::<Music>()
+        .add_startup_system(spawn_cameras)
+        .add_systems((fit_to_window, toggle_fullscreen, remember_window_size))
+        .add_startup_system(load_sounds)
+        .add_systems((play_sounds, fade_music))
+        .add_system(update_actions.in_base_set(CoreSet::PreUpdate).after(InputSystem))
+        .add_system(save_settings)
+        .add_startup_system(load_level_pack)
+        .add_system(wait_for_level_pack.in_set(OnUpdate(GameState::Loading)))
+        .add_system(reload_level_pack)
         .add_system(spawn_map.in_schedule(OnEnter(GameState::Starting)))
+        .add_system(spawn_hud.in_schedule(OnEnter(GameState::Starting)))
+        .add_system(play_level_music.in_schedule(OnEnter(GameState::Starting)))
+        .add_system(update_hud)
         .add_system(transition_map.in_set(OnUpdate(GameState::Starting)))
+        .add_system(frame_map.in_set(OnUpdate(GameState::Starting)))
+        .add_system(frame_map.in_set(OnUpdate(GameState::Playing)))
         .add_systems((
                 animate_sprite,
+                request_hint,
+                poll_solver,
+                plan_autoplay.before(player_move),
+                click_to_move.before(player_move),
                 player_move,
-                entity_update,
-                win_condition,
+                undo_move,
+                detect_deadlock.after(player_move).after(undo_move),
+                show_deadlock_banner.after(poll_deadlock),
+                animate_tweens.after(start_tweens),
+                win_condition.after(player_move),
+                tick_level_timer.before(win_condition),
                 reset_map, 
+                open_level_select,
+                pause_game,
              ).in_set(OnUpdate(GameState::Playing)))
+        .add_systems((
+                expire_drag_notice,
+                poll_deadlock,
+                update_tiles.after(player_move).after(undo_move),
+                animate_doors.after(update_tiles),
+                start_tweens.after(player_move).after(undo_move),
+            ).in_set(OnUpdate(GameState::Playing)))
+        .add_system(despawn_level.in_schedule(OnEnter(GameState::MainMenu)))
+        .add_system(spawn_main_menu.in_schedule(OnEnter(GameState::MainMenu)))
+        .add_system(despawn_menu.in_schedule(OnExit(GameState::MainMenu)))
+        .add_systems((freeze_timers, spawn_pause_menu).in_schedule(OnEnter(GameState::Paused)))
+        .add_systems((unfreeze_timers, despawn_menu).in_schedule(OnExit(GameState::Paused)))
+        .add_systems((navigate_menu, draw_menu.after(navigate_menu))
+            .in_set(OnUpdate(GameState::MainMenu)))
+        .add_systems((navigate_menu, draw_menu.after(navigate_menu))
+            .in_set(OnUpdate(GameState::Paused)))
         .add_system(init_clear_map.in_schedule(OnEnter(GameState::Resetting)))
+        .add_system(reset_level_stats.in_schedule(OnEnter(GameState::Resetting)))
         .add_system(init_clear_map.in_schedule(OnEnter(GameState::NextLevel)))
+        .add_system(save_progress.in_schedule(OnEnter(GameState::NextLevel)))
+        .add_systems((cue_level_complete, play_next_level_music)
+            .in_schedule(OnEnter(GameState::NextLevel)))
         .add_system(clear_map.in_set(OnUpdate(GameState::Resetting)))
         .add_system(clear_map.in_set(OnUpdate(GameState::NextLevel)))
         .add_system(spawn_gameover.in_schedule(OnEnter(GameState::GameOver)))
         .add_system(clear_gameover.in_schedule(OnExit(GameState::GameOver)))
         .add_system(startover.in_set(OnUpdate(GameState::GameOver)))
-        .run();
+        .add_systems((paste_replay, drive_replay.before(player_move), show_replay_banner)
+            .in_set(OnUpdate(GameState::Playing)))
+        .add_system(rewind_replay.in_schedule(OnEnter(GameState::Starting)))
+        .add_system(stop_replay.in_schedule(OnEnter(GameState::NextLevel)))
+        .add_system(stop_replay.in_schedule(OnEnter(GameState::MainMenu)))
+        .add_system(stop_replay.in_schedule(OnEnter(GameState::LevelSelect)))
+        .add_system(despawn_level.in_schedule(OnEnter(GameState::LevelSelect)))
+        .add_system(enter_level_select.in_schedule(OnEnter(GameState::LevelSelect)))
+        .add_systems((navigate_level_select, draw_level_select.after(navigate_level_select))
+            .in_set(OnUpdate(GameState::LevelSelect)))
+        .add_system(exit_level_select.in_schedule(OnExit(GameState::LevelSelect)))
+        .add_system(end_playtest.in_schedule(OnEnter(GameState::LevelSelect)))
+        .add_systems((despawn_level, stop_replay, end_playtest, enter_editor.after(despawn_level))
+            .in_schedule(OnEnter(GameState::Editor)))
+        .add_systems((paint_map, editor_controls, draw_editor.after(paint_map).after(editor_controls),
+                frame_editor)
+            .in_set(OnUpdate(GameState::Editor)))
+        .add_system(exit_editor.in_schedule(OnExit(GameState::Editor)));
+
+    if let Some(replay) = replay {
+        app.insert_resource(replay);
+    }
+    app.run();
 }
@@ -1,8 +1,19 @@
-use bevy::prelude::*;
+use bevy::{asset::LoadState, prelude::*};
 
-use crate::{components::*, levels::LEVELS};
+use crate::{components::*, levels::{LevelPack, Tile}};
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::Direction;
+use crate::{GameLevelPack, LevelPackPath};
 use crate::{GameState, GameLevel};
 use crate::load_asset_atlas;
+use crate::board::SokobanBoard;
+use crate::deadlock::Deadlock;
+use crate::editor::Playtest;
+use crate::hint::Autoplay;
+use crate::input::{Action, ActionState};
+use crate::hud::LevelStats;
+use crate::solver::SolverGrid;
+use crate::history::MoveHistory;
 
 fn create_tile_bundle(sprite_index: usize, texture_atlas: Handle<TextureAtlas>, transform: Transform)
     -> SpriteSheetBundle {
@@ -15,9 +26,88 @@ fn create_tile_bundle(sprite_index: usize, texture_atlas: Handle<TextureAtlas>,
     }
 }
 
+pub fn load_level_pack(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    level_pack_path: Res<LevelPackPath>) {
+
+    commands.insert_resource(GameLevelPack(asset_server.load(level_pack_path.0.as_str())));
+}
+
+pub fn wait_for_level_pack(
+    asset_server: Res<AssetServer>,
+    level_pack: Res<GameLevelPack>,
+    level_pack_path: Res<LevelPackPath>,
+    mut game_level: ResMut<GameLevel>,
+    level_packs: Res<Assets<LevelPack>>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    if let Some(pack) = level_packs.get(&level_pack.0) {
+        game_level.0 = game_level.0.min(pack.levels.len() - 1);
+        next_state.set(GameState::MainMenu);
+    } else if matches!(asset_server.get_load_state(&level_pack.0), LoadState::Failed) {
+        error!("Could not load the level pack {}", level_pack_path.0);
+        std::process::exit(1);
+    }
+}
+
+// Restart the current level whenever the level pack changes on disk
+pub fn reload_level_pack(
+    mut events: EventReader<AssetEvent<LevelPack>>,
+    game_state: Res<State<GameState>>,
+    mut game_level: ResMut<GameLevel>,
+    level_packs: Res<Assets<LevelPack>>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    for event in events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if let Some(pack) = level_packs.get(handle) {
+                game_level.0 = game_level.0.min(pack.levels.len() - 1);
+            }
+            // Maps that are being cleared already respawn from the new pack
+            if matches!(game_state.0, GameState::Playing | GameState::Paused) {
+                next_state.set(GameState::Resetting);
+            }
+        }
+    }
+}
+
+// Tints that tell the switches and gates of each letter apart
+pub const SWITCH_COLORS: [Color; 3] =
+    [Color::rgb(1., 0.45, 0.4), Color::rgb(0.5, 1., 0.5), Color::rgb(0.55, 0.7, 1.)];
+// Keys and the doors they open
+pub const KEY_COLOR: Color = Color::GOLD;
+// Ice and holes are tinted floor, one-way floors have a bar on the side
+// they're entered from
+pub const ICE_COLOR: Color = Color::rgb(0.6, 0.85, 1.);
+pub const HOLE_COLOR: Color = Color::rgb(0.1, 0.08, 0.08);
+pub const ONE_WAY_COLOR: Color = Color::rgb(1., 0.8, 0.3);
+// Boxes resting on a goal or switch
+pub const BOX_ON_GOAL_COLOR: Color = Color::rgb(0.6, 1., 0.55);
+// Boxes that filled a hole, level with the floor
+pub const SUNK_BOX_COLOR: Color = Color::rgb(0.45, 0.4, 0.35);
+
+// The bar sprite is on the left edge, for floors entered going right
+pub fn one_way_rotation(direction: Direction) -> Quat {
+    Quat::from_rotation_z(match direction {
+        Direction::Right => 0.,
+        Direction::Down => -std::f32::consts::FRAC_PI_2,
+        Direction::Left => std::f32::consts::PI,
+        Direction::Up => std::f32::consts::FRAC_PI_2,
+    })
+}
+
+// Where the top left tile of a map goes, so the whole map is centered
+pub fn map_origin(map_size: MapSize) -> Vec2 {
+    Vec2::new(-(map_size.width as f32 * 8.) / 2., (map_size.height as f32 * 8.) / 2.)
+}
+
 pub fn spawn_map(mut commands: Commands, 
     asset_server: Res<AssetServer>, 
     game_level: Res<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>,
+    playtest: Option<Res<Playtest>>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>) {
 
     let player_atlas_handle = load_asset_atlas(&asset_server, &mut texture_atlases, 
@@ -26,128 +116,148 @@ pub fn spawn_map(mut commands: Commands,
     let atlas_handle = load_asset_atlas(&asset_server, &mut texture_atlases,
         "sprites/DungeonTiles.png", 4, 2, None, None);
 
-    let level = game_level.0;
-    let map_level = LEVELS[level].trim();
-    let mut map_size = MapSize { width: 0, height: 1 };
-    let mut triggers = Vec::new();
+    let parsed = match playtest {
+        Some(playtest) => playtest.0.clone(),
+        None => level_packs.get(&level_pack.0).unwrap().levels[game_level.0].parse()
+            .expect("levels are validated when the pack is loaded"),
+    };
+    let map_size = parsed.size;
 
-    let mut x = 0;
-    let mut y = 0;
+    let mut map_tiles = TileStorage::new(map_size);
 
-    for c in map_level.chars() {
-        if c == '\n' {
-            if map_size.width < x {
-                map_size.width = x;
-            }
-            x = 0;
-            map_size.height += 1;
-        } else {
-            x += 1;
+    let origin = map_origin(map_size);
+    let center_transform = Transform::from_xyz(origin.x, origin.y, 0.);
+
+    for (index, tile) in parsed.tiles.iter().enumerate() {
+        if matches!(tile, Tile::Outside) {
+            continue;
         }
-    }
 
-    let mut map_tiles = TileStorage::new(map_size);
+        let x = index as i32 % map_size.width;
+        let y = index as i32 / map_size.width;
+        let world_pos = WorldPosition {
+            x: (x as f32 * 8.) + center_transform.translation.x,
+            y: (y as f32 * -8.) + center_transform.translation.y
+        };
 
-    x = 0;
+        let transform = 
+            Transform::from_xyz(world_pos.x, -120., 0.);
+        
+        let tile_pos = TilePos::new(x, y, map_size.width);
 
-    let center_transform = Transform::from_xyz(
-        -(map_size.width as f32 * 8.) / 2., 
-        (map_size.height as f32 * 8.) / 2., 0.);
+        let mut floor = create_tile_bundle(0, atlas_handle.clone(), transform);
+        floor.sprite.color = match tile {
+            Tile::Ice => ICE_COLOR,
+            Tile::Hole => HOLE_COLOR,
+            _ => Color::WHITE,
+        };
+        commands.spawn((floor, world_pos, tile_pos));
 
-    for c in map_level.chars() {
-        if c == '\n' {
-            y += 1;
-            x = 0;
-        } else {
-            let world_pos = WorldPosition {
-                x: (x as f32 * 8.) + center_transform.translation.x,
-                y: (y as f32 * -8.) + center_transform.translation.y
-            };
+        if let Tile::OneWay(direction) = tile {
+            let mut bundle = create_tile_bundle(5, atlas_handle.clone(),
+                Transform::from_xyz(world_pos.x, -120., 1.).with_rotation(one_way_rotation(*direction)));
+            bundle.sprite.color = ONE_WAY_COLOR;
+            commands.spawn((bundle, world_pos, tile_pos));
+        }
 
-            let transform = 
-                Transform::from_xyz(world_pos.x, -120., 0.);
-            
-            let tile_pos = TilePos::new(x, y, map_size.width);
+        let mut transform = 
+            Transform::from_xyz(world_pos.x, -120.,3.);
 
-            commands.spawn((
-                create_tile_bundle(0, atlas_handle.clone(), transform),
+        if matches!(tile, Tile::Door | Tile::Gate(_) | Tile::Lock) {
+            // Rotate the door if on the sides
+            if x == 0 {
+                transform.rotate_z(std::f32::consts::FRAC_PI_2);
+            } else if x + 1 == map_size.width {
+                transform.rotate_z(-std::f32::consts::FRAC_PI_2);
+            }
+        }
+
+        if index == parsed.player {
+            let mut timer = Timer::from_seconds(MOVE_SECONDS, TimerMode::Once);
+            timer.pause();
+            let entity = commands.spawn(PlayerBundle {
+                sprite_sheet_bundle:
+                    create_tile_bundle(0, player_atlas_handle.clone(), transform),
+                player: Player,
                 world_pos,
                 tile_pos,
+                animation_timer: AnimationTimer(
+                    Timer::from_seconds(0.1, TimerMode::Repeating)),
+                animation_indices: AnimationIndices::IDLE,
+                move_cooldown: MoveTimer(timer)
+            }).id();
+            map_tiles.tiles[tile_pos.index] = Some(entity);
+        }
+
+        if parsed.goals.contains(&index) {
+            let transform = Transform::from_xyz(world_pos.x, -120., 2.);
+
+            commands.spawn((
+                create_tile_bundle(1, atlas_handle.clone(), transform),
+                world_pos,
+                tile_pos
             ));
 
-            let mut transform = 
-                Transform::from_xyz(world_pos.x, -120.,3.);
+        }
 
-            if c == 'D' {
-                // Rotate the door if on the sides
-                if x == 0 {
-                    transform.rotate_z(1.570796);
-                } else if x + 1 == map_size.width {
-                    transform.rotate_z(-1.570796);
-                }
-            }
+        if let Tile::Switch(letter) = tile {
+            let mut bundle = create_tile_bundle(1, atlas_handle.clone(),
+                Transform::from_xyz(world_pos.x, -120., 2.));
+            bundle.sprite.color = SWITCH_COLORS[*letter as usize];
+            commands.spawn((bundle, world_pos, tile_pos));
+        }
 
-            if c == '@' {
-                let mut timer = Timer::from_seconds(0.4, TimerMode::Once);
-                timer.pause();
-                let entity = commands.spawn(PlayerBundle {
-                    sprite_sheet_bundle:
-                        create_tile_bundle(0, player_atlas_handle.clone(), transform),
-                    player: Player,
-                    world_pos,
-                    tile_pos,
-                    animation_timer: AnimationTimer(
-                        Timer::from_seconds(0.1, TimerMode::Repeating)),
-                    animation_indices: AnimationIndices { first: 0, last: 7 },
-                    move_cooldown: MoveTimer(timer)
-                }).id();
-                map_tiles.tiles[tile_pos.index] = Some(entity);
-            } else if c == 'o' {
-                let transform = Transform::from_xyz(world_pos.x, -120., 2.);
-
-                commands.spawn((
-                    create_tile_bundle(1, atlas_handle.clone(), transform),
-                    world_pos,
-                    tile_pos
-                ));
-
-                triggers.push(tile_pos.index);
-            } else if c == 'b' {
-                let entity = commands.spawn((
-                    create_tile_bundle(3, atlas_handle.clone(), transform),
-                    tile_pos, BlockType::Box,
-                    world_pos,
-                )).id();
-                map_tiles.tiles[tile_pos.index] = Some(entity);
-            }
+        if parsed.keys.contains(&index) {
+            let mut bundle = create_tile_bundle(1, atlas_handle.clone(),
+                Transform::from_xyz(world_pos.x, -120., 2.).with_scale(Vec3::splat(0.6)));
+            bundle.sprite.color = KEY_COLOR;
+            commands.spawn((bundle, world_pos, tile_pos, Key));
+        }
 
-            if let Some((sprite_index, block_type)) = match c {
-                '#' => Some((2, BlockType::Wall)),
-                'D' => {
-                    commands.spawn(DoorIndex(tile_pos.index));
-                    Some((4, BlockType::Door))
-                },
-                _ => None
-            } {
-                let entity = commands.spawn((
-                    create_tile_bundle(sprite_index, atlas_handle.clone(), transform),
-                    world_pos,
-                    tile_pos,
-                    block_type
-                )).id();
-                map_tiles.tiles[tile_pos.index] = Some(entity);
-            }
+        if parsed.boxes.contains(&index) {
+            let entity = commands.spawn((
+                create_tile_bundle(3, atlas_handle.clone(), transform),
+                tile_pos, BlockType::Box,
+                world_pos,
+            )).id();
+            map_tiles.tiles[tile_pos.index] = Some(entity);
+        }
 
-            x += 1;
+        if let Tile::Wall = tile {
+            let entity = commands.spawn((
+                create_tile_bundle(2, atlas_handle.clone(), transform),
+                world_pos,
+                tile_pos,
+                BlockType::Wall
+            )).id();
+            map_tiles.tiles[tile_pos.index] = Some(entity);
         }
 
+        // Doors are left out of the tile storage, `update_tiles` opens and closes them
+        if let Some((block_type, color)) = match tile {
+            Tile::Door => Some((BlockType::Door, Color::WHITE)),
+            Tile::Gate(letter) => Some((BlockType::Gate, SWITCH_COLORS[*letter as usize])),
+            Tile::Lock => Some((BlockType::Lock, KEY_COLOR)),
+            _ => None
+        } {
+            let mut bundle = create_tile_bundle(4, atlas_handle.clone(), transform);
+            bundle.sprite.color = color;
+            commands.spawn((bundle, world_pos, tile_pos, block_type, DoorSwing::default()));
+        }
     }
     commands.spawn(map_tiles);
-    commands.spawn(TriggerIndices(triggers));
+    let board = SokobanBoard::new(&parsed);
+    commands.spawn(DeadSquares(SolverGrid::from_board(&board).dead_squares()));
+    commands.spawn(board);
+    commands.insert_resource(MoveHistory::default());
+    commands.insert_resource(Autoplay::default());
+    commands.insert_resource(Deadlock::default());
+    commands.insert_resource(LevelStats::default());
     // For initial transition
     commands.spawn(AnimationTimer(Timer::from_seconds(1., TimerMode::Once)));
 }
 
+#[allow(clippy::type_complexity)]
 pub fn transition_map(
     time: Res<Time>,
     mut commands: Commands,
@@ -176,8 +286,18 @@ pub fn transition_map(
     }
 }
 
-pub fn reset_map(keyboard_input: Res<Input<KeyCode>>, mut next_state: ResMut<NextState<GameState>>) {
-    if keyboard_input.just_pressed(KeyCode::R) {
+// Clears whatever is on screen, be it a level, a menu or the game over text
+pub fn despawn_level(
+    mut commands: Commands,
+    entities: Query<Entity, (Without<Camera>, Without<Window>)>) {
+
+    for entity in &entities {
+        commands.entity(entity).despawn();
+    }
+}
+
+pub fn reset_map(actions: Res<ActionState>, mut next_state: ResMut<NextState<GameState>>) {
+    if actions.just_pressed(Action::Restart) {
         next_state.set(GameState::Resetting);
     }
 }
@@ -196,11 +316,15 @@ pub fn init_clear_map(
     ));
 }
 
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn clear_map(
     mut commands: Commands,
     timer: Res<Time>,
     game_state: Res<State<GameState>>,
     mut game_level: ResMut<GameLevel>,
+    level_pack: Res<GameLevelPack>,
+    level_packs: Res<Assets<LevelPack>>,
+    playtest: Option<Res<Playtest>>,
     mut game_state_next: ResMut<NextState<GameState>>,
     mut tiles_q: Query<(Entity, Option<&TilePos>, Option<&mut Transform>), (Without<Camera>, Without<Window>)>,
     mut timer_q: Query<(&mut AnimationTimer, &WorldPosition), Without<Player>>) {
@@ -211,10 +335,15 @@ pub fn clear_map(
             for (entity, _, _) in &tiles_q {
                 commands.entity(entity).despawn();
             }
-            if matches!(game_state.0, GameState::NextLevel) {
+            if matches!(game_state.0, GameState::NextLevel) && playtest.is_some() {
+                // A solved playtest goes back to editing
+                game_state_next.set(GameState::Editor);
+            } else if matches!(game_state.0, GameState::NextLevel) {
                 let level = game_level.0;
                 let new_level = level + 1;
-                if new_level < LEVELS.len() {
+                let level_count = level_packs.get(&level_pack.0)
+                    .map_or(0, |pack| pack.levels.len());
+                if new_level < level_count {
                     game_level.0 = new_level;
                     game_state_next.set(GameState::Starting);
                 } else {
@@ -0,0 +1,292 @@
+use bevy::{app::AppExit, prelude::*};
+
+use crate::components::{AnimationTimer, Direction, MoveTimer};
+use crate::editor::Playtest;
+use crate::input::{Action, ActionState};
+use crate::settings::Settings;
+use crate::GameState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuEntry {
+    Continue,
+    RestartLevel,
+    LevelSelect,
+    Editor,
+    Settings,
+    Quit,
+    ToggleHud,
+    ToggleDeadlockWarnings,
+    MusicVolume,
+    SoundVolume,
+    Back,
+}
+
+const MAIN_MENU: [MenuEntry; 5] = [MenuEntry::Continue, MenuEntry::LevelSelect, MenuEntry::Editor,
+    MenuEntry::Settings, MenuEntry::Quit];
+const PAUSE_MENU: [MenuEntry; 5] = [MenuEntry::Continue, MenuEntry::RestartLevel,
+    MenuEntry::LevelSelect, MenuEntry::Settings, MenuEntry::Quit];
+const SETTINGS_MENU: [MenuEntry; 5] = [MenuEntry::ToggleHud, MenuEntry::ToggleDeadlockWarnings,
+    MenuEntry::MusicVolume, MenuEntry::SoundVolume, MenuEntry::Back];
+
+impl MenuEntry {
+    fn label(self, state: &GameState, settings: &Settings) -> String {
+        let on_off = |on| if on { "on" } else { "off" };
+        let percent = |volume: f32| (volume * 100.).round() as i32;
+        match self {
+            MenuEntry::Continue => String::from("Continue"),
+            MenuEntry::RestartLevel => String::from("Restart level"),
+            MenuEntry::LevelSelect => String::from("Level select"),
+            MenuEntry::Editor => String::from("Level editor"),
+            MenuEntry::Settings => String::from("Settings"),
+            MenuEntry::Quit if *state == GameState::Paused => String::from("Quit to menu"),
+            MenuEntry::Quit => String::from("Quit"),
+            MenuEntry::ToggleHud => format!("HUD: {}", on_off(settings.show_hud)),
+            MenuEntry::ToggleDeadlockWarnings =>
+                format!("Deadlock warning: {}", on_off(settings.deadlock_warnings)),
+            MenuEntry::MusicVolume => format!("Music: {}%", percent(settings.music_volume)),
+            MenuEntry::SoundVolume => format!("Sounds: {}%", percent(settings.sound_volume)),
+            MenuEntry::Back => String::from("Back"),
+        }
+    }
+}
+
+// The menu on screen, shared by the main and pause menus
+#[derive(Component)]
+pub struct Menu {
+    pub entries: Vec<MenuEntry>,
+    pub selected: usize,
+}
+
+#[derive(Component)]
+pub struct MenuItem(pub usize);
+
+// Moves the cursor up or down, wrapping around the ends
+fn cycle(selected: usize, step: i32, count: usize) -> usize {
+    (selected as i32 + step).rem_euclid(count as i32) as usize
+}
+
+// Volumes go up and down in tenths, wrapping around as well
+fn step_volume(volume: f32, step: i32) -> f32 {
+    cycle((volume * 10.).round() as usize, step, 11) as f32 / 10.
+}
+
+fn spawn_menu(commands: &mut Commands, asset_server: &AssetServer, title: &str,
+    entries: &[MenuEntry], background: Color) {
+
+    let font = asset_server.load("Minimal3x5.ttf");
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                position_type: PositionType::Absolute,
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                gap: Size::height(Val::Px(2.)),
+                ..default()
+            },
+            background_color: background.into(),
+            // Above the level when pausing
+            z_index: ZIndex::Global(10),
+            ..default()
+        },
+        Menu { entries: entries.to_vec(), selected: 0 },
+    )).with_children(|menu| {
+        menu.spawn(TextBundle::from_section(title, TextStyle {
+            font: font.clone(),
+            font_size: 10.0,
+            color: Color::WHITE,
+        }).with_style(Style {
+            margin: UiRect::bottom(Val::Px(4.)),
+            ..default()
+        }));
+        // The most entries any menu has, labels are filled in by `draw_menu`
+        for index in 0..MAIN_MENU.len().max(PAUSE_MENU.len()).max(SETTINGS_MENU.len()) {
+            menu.spawn((
+                TextBundle::from_section("", TextStyle {
+                    font: font.clone(),
+                    font_size: 5.0,
+                    color: Color::WHITE,
+                }),
+                MenuItem(index),
+            ));
+        }
+    });
+}
+
+pub fn spawn_main_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    spawn_menu(&mut commands, &asset_server, "Sokoban", &MAIN_MENU, Color::NONE);
+}
+
+pub fn spawn_pause_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    spawn_menu(&mut commands, &asset_server, "Paused", &PAUSE_MENU, Color::rgba(0., 0., 0., 0.7));
+}
+
+pub fn despawn_menu(mut commands: Commands, menu_query: Query<Entity, With<Menu>>) {
+    for entity in &menu_query {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+pub fn pause_game(
+    actions: Res<ActionState>,
+    playtest: Option<Res<Playtest>>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    if actions.just_pressed(Action::Pause) {
+        // Playtests go straight back to the editor
+        next_state.set(if playtest.is_some() { GameState::Editor } else { GameState::Paused });
+    }
+}
+
+// Nothing ticks these outside of `Playing` anyway, but stopping them makes
+// sure the moves carry on exactly where they left off
+pub fn freeze_timers(
+    mut move_timers: Query<&mut MoveTimer>,
+    mut animation_timers: Query<&mut AnimationTimer>) {
+
+    for mut timer in &mut move_timers {
+        timer.pause();
+    }
+    for mut timer in &mut animation_timers {
+        timer.pause();
+    }
+}
+
+pub fn unfreeze_timers(
+    mut move_timers: Query<&mut MoveTimer>,
+    mut animation_timers: Query<&mut AnimationTimer>) {
+
+    for mut timer in &mut move_timers {
+        timer.unpause();
+    }
+    for mut timer in &mut animation_timers {
+        timer.unpause();
+    }
+}
+
+pub fn navigate_menu(
+    actions: Res<ActionState>,
+    game_state: Res<State<GameState>>,
+    mut settings: ResMut<Settings>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut exit: EventWriter<AppExit>,
+    mut menu_query: Query<&mut Menu>) {
+
+    let Ok(mut menu) = menu_query.get_single_mut() else { return };
+    let count = menu.entries.len();
+
+    if actions.just_pressed(Action::Move(Direction::Up)) {
+        menu.selected = cycle(menu.selected, -1, count);
+    } else if actions.just_pressed(Action::Move(Direction::Down)) {
+        menu.selected = cycle(menu.selected, 1, count);
+    } else if let Some(step) = [(Direction::Left, -1), (Direction::Right, 1)].into_iter()
+        .find_map(|(direction, step)| actions.just_pressed(Action::Move(direction)).then_some(step)) {
+        // Left and right turn the selected volume down and up
+        match menu.entries[menu.selected] {
+            MenuEntry::MusicVolume => settings.music_volume = step_volume(settings.music_volume, step),
+            MenuEntry::SoundVolume => settings.sound_volume = step_volume(settings.sound_volume, step),
+            _ => {},
+        }
+    } else if actions.just_pressed(Action::Pause) {
+        if menu.entries != SETTINGS_MENU {
+            // Escape resumes the game from the pause menu
+            if game_state.0 == GameState::Paused {
+                next_state.set(GameState::Playing);
+            }
+            return;
+        }
+        close_settings(&mut menu, &game_state.0);
+    } else if actions.just_pressed(Action::Confirm) {
+        match menu.entries[menu.selected] {
+            MenuEntry::Continue if game_state.0 == GameState::Paused =>
+                next_state.set(GameState::Playing),
+            MenuEntry::Continue => next_state.set(GameState::Starting),
+            MenuEntry::RestartLevel => next_state.set(GameState::Resetting),
+            MenuEntry::LevelSelect => next_state.set(GameState::LevelSelect),
+            MenuEntry::Editor => next_state.set(GameState::Editor),
+            MenuEntry::Settings => {
+                menu.entries = SETTINGS_MENU.to_vec();
+                menu.selected = 0;
+            },
+            MenuEntry::Quit if game_state.0 == GameState::Paused =>
+                next_state.set(GameState::MainMenu),
+            MenuEntry::Quit => exit.send(AppExit),
+            MenuEntry::ToggleHud => settings.show_hud = !settings.show_hud,
+            MenuEntry::ToggleDeadlockWarnings =>
+                settings.deadlock_warnings = !settings.deadlock_warnings,
+            MenuEntry::MusicVolume => settings.music_volume = step_volume(settings.music_volume, 1),
+            MenuEntry::SoundVolume => settings.sound_volume = step_volume(settings.sound_volume, 1),
+            MenuEntry::Back => close_settings(&mut menu, &game_state.0),
+        }
+    }
+}
+
+fn close_settings(menu: &mut Menu, state: &GameState) {
+    menu.entries = if *state == GameState::Paused { PAUSE_MENU.to_vec() } else { MAIN_MENU.to_vec() };
+    menu.selected = menu.entries.iter().position(|entry| *entry == MenuEntry::Settings).unwrap_or(0);
+}
+
+pub fn draw_menu(
+    game_state: Res<State<GameState>>,
+    settings: Res<Settings>,
+    menu_query: Query<Ref<Menu>>,
+    mut item_query: Query<(&MenuItem, &mut Text, &mut Visibility)>) {
+
+    let Ok(menu) = menu_query.get_single() else { return };
+    // Redraw when the cursor moves or a setting was toggled
+    if !menu.is_changed() && !settings.is_changed() {
+        return;
+    }
+
+    for (MenuItem(index), mut text, mut visibility) in &mut item_query {
+        let Some(entry) = menu.entries.get(*index) else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+        *visibility = Visibility::Inherited;
+        let section = &mut text.sections[0];
+        if *index == menu.selected {
+            section.value = format!("> {} <", entry.label(&game_state.0, &settings));
+            section.style.color = Color::YELLOW;
+        } else {
+            section.value = entry.label(&game_state.0, &settings);
+            section.style.color = Color::WHITE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_cursor_wraps_around() {
+        assert_eq!(cycle(0, -1, 4), 3);
+        assert_eq!(cycle(3, 1, 4), 0);
+        assert_eq!(cycle(1, 1, 4), 2);
+    }
+
+    #[test]
+    fn closing_the_settings_returns_to_the_settings_entry() {
+        let mut menu = Menu { entries: SETTINGS_MENU.to_vec(), selected: 4 };
+        close_settings(&mut menu, &GameState::Paused);
+        assert_eq!(menu.entries, PAUSE_MENU);
+        assert_eq!(menu.entries[menu.selected], MenuEntry::Settings);
+    }
+
+    #[test]
+    fn toggles_show_their_setting() {
+        let settings = Settings { show_hud: false, ..default() };
+        assert_eq!(MenuEntry::ToggleHud.label(&GameState::MainMenu, &settings), "HUD: off");
+        assert_eq!(MenuEntry::Quit.label(&GameState::Paused, &settings), "Quit to menu");
+        assert_eq!(MenuEntry::MusicVolume.label(&GameState::Paused, &settings), "Music: 50%");
+    }
+
+    #[test]
+    fn volumes_step_in_tenths_and_wrap_around() {
+        assert_eq!(step_volume(0.5, 1), 0.6);
+        assert_eq!(step_volume(1., 1), 0.);
+        assert_eq!(step_volume(0., -1), 1.);
+    }
+}
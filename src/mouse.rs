@@ -0,0 +1,187 @@
+use bevy::{prelude::*, window::PrimaryWindow};
+
+use crate::board::SokobanBoard;
+use crate::camera::{cursor_to_world, GameCamera};
+use crate::components::MapSize;
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::Direction;
+use crate::hint::Autoplay;
+use crate::map::map_origin;
+use crate::replay::Replay;
+use crate::solver::{PushPlanError, SolverGrid};
+
+// The tile under a point of the world, if it's on the map
+pub fn tile_at(map_size: MapSize, point: Vec2) -> Option<usize> {
+    let offset = point - map_origin(map_size);
+    let x = (offset.x / 8.).round() as i32;
+    let y = (-offset.y / 8.).round() as i32;
+    (x >= 0 && x < map_size.width && y >= 0 && y < map_size.height)
+        .then(|| (x + y * map_size.width) as usize)
+}
+
+// Steps that take the player to the clicked tile. Clicking a box right next
+// to the player pushes it away from them instead.
+pub fn click_steps(board: &SokobanBoard, target: usize) -> Option<Vec<Direction>> {
+    if board.has_box(target) {
+        let direction = Direction::ALL.into_iter()
+            .find(|direction| board.neighbour(board.player, *direction) == Some(target))?;
+        return board.can_push(target, direction).then(|| vec![direction]);
+    }
+
+    if target == board.player {
+        return None;
+    }
+    board.walk_path(target)
+}
+
+// Tile where the left mouse button went down, to tell clicks from drags
+#[derive(Resource, Default)]
+pub struct DragStart(pub Option<usize>);
+
+// Explains why a dragged box stays put, shown for a couple of seconds
+#[derive(Component)]
+pub struct DragNotice(pub Timer);
+
+fn drag_notice_text(err: PushPlanError) -> &'static str {
+    match err {
+        PushPlanError::Blocked => "Can't push the box there - blocked",
+        PushPlanError::DeadSquare => "Can't push the box there - dead square",
+    }
+}
+
+// Clicking a tile walks there, dragging a box onto another tile pushes it there
+#[allow(clippy::too_many_arguments)]
+pub fn click_to_move(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mouse_input: Res<Input<MouseButton>>,
+    mut drag_start: ResMut<DragStart>,
+    mut autoplay: ResMut<Autoplay>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<GameCamera>>,
+    board_query: Query<&SokobanBoard>,
+    replay: Option<Res<Replay>>,
+    notice_query: Query<Entity, With<DragNotice>>) {
+
+    let pressed = mouse_input.just_pressed(MouseButton::Left);
+    if (!pressed && !mouse_input.just_released(MouseButton::Left)) || replay.is_some() {
+        return;
+    }
+    let (Ok(window), Ok((camera, camera_transform)), Ok(board))
+        = (window_query.get_single(), camera_query.get_single(), board_query.get_single())
+        else { return };
+
+    let tile = cursor_to_world(window, camera, camera_transform)
+        .and_then(|point| tile_at(board.size, point));
+    if pressed {
+        drag_start.0 = tile;
+        return;
+    }
+    let (Some(start), Some(target)) = (drag_start.0.take(), tile) else { return };
+
+    if start == target {
+        if let Some(steps) = click_steps(board, target) {
+            // A click takes over from any hint being played, same as the keys
+            autoplay.clear();
+            autoplay.steps = steps.into();
+        }
+    } else if board.has_box(start) {
+        match SolverGrid::from_board(board).push_path(start, target) {
+            // `plan_autoplay` adds the walks between the pushes
+            Ok(pushes) => {
+                autoplay.clear();
+                autoplay.pushes = pushes.into();
+            },
+            Err(err) => {
+                for entity in &notice_query {
+                    commands.entity(entity).despawn();
+                }
+                commands.spawn((
+                    TextBundle::from_section(
+                        drag_notice_text(err),
+                        TextStyle {
+                            font: asset_server.load("Minimal3x5.ttf"),
+                            font_size: 5.0,
+                            color: Color::ORANGE_RED,
+                        },
+                    )
+                    .with_style(Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            top: Val::Px(9.),
+                            ..default()
+                        },
+                        margin: UiRect::horizontal(Val::Auto),
+                        ..default()
+                    }),
+                    DragNotice(Timer::from_seconds(2., TimerMode::Once)),
+                ));
+            },
+        }
+    }
+}
+
+pub fn expire_drag_notice(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut notice_query: Query<(Entity, &mut DragNotice)>) {
+
+    for (entity, mut notice) in &mut notice_query {
+        if notice.0.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::load;
+
+    #[test]
+    fn points_map_to_the_nearest_tile() {
+        let size = MapSize { width: 4, height: 3 };
+        let origin = map_origin(size);
+        assert_eq!(tile_at(size, origin), Some(0));
+        assert_eq!(tile_at(size, origin + Vec2::new(8. * 3. + 3., -8. * 2. - 3.)), Some(11));
+        assert_eq!(tile_at(size, origin + Vec2::new(-8., 0.)), None);
+        assert_eq!(tile_at(size, origin + Vec2::new(0., -8. * 3.)), None);
+    }
+
+    #[test]
+    fn clicks_walk_around_walls_and_boxes() {
+        let board = load("#######\n#@$  .#\n#   # #\n#######");
+        let steps = click_steps(&board, 5 + 2 * 7).unwrap();
+        assert_eq!(steps, vec![Direction::Down, Direction::Right, Direction::Right,
+            Direction::Up, Direction::Right, Direction::Right, Direction::Down]);
+    }
+
+    #[test]
+    fn clicks_walk_through_locked_doors_with_a_key_on_the_way() {
+        let board = load("#########\n#@k K $.#\n#########");
+        assert_eq!(click_steps(&board, 5 + 9), Some(vec![Direction::Right; 4]));
+    }
+
+    #[test]
+    fn unreachable_tiles_and_walls_are_ignored() {
+        let board = load("#######\n#@$#. #\n#######");
+        // Behind the wall, the wall itself and where the player already is
+        assert_eq!(click_steps(&board, 11), None);
+        assert_eq!(click_steps(&board, 10), None);
+        assert_eq!(click_steps(&board, 8), None);
+    }
+
+    #[test]
+    fn clicking_a_neighbouring_box_pushes_it() {
+        let board = load("######\n#@$ .#\n######");
+        assert_eq!(click_steps(&board, 8), Some(vec![Direction::Right]));
+
+        // Unless something's in the way
+        let board = load("######\n#@$$.#\n#   .#\n######");
+        assert_eq!(click_steps(&board, 8), None);
+
+        // Holes take boxes the same as with the keys
+        let board = load("########\n#@$x $.#\n########");
+        assert_eq!(click_steps(&board, 10), Some(vec![Direction::Right]));
+    }
+}
@@ -1,6 +1,17 @@
 use bevy::prelude::*;
 
 use crate::{components::*, GameState};
+use crate::audio::{move_sounds, PlaySound};
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::Direction;
+use crate::board::{MoveOutcome, SokobanBoard};
+use crate::levels::Tile;
+use crate::hint::Autoplay;
+use crate::hud::LevelStats;
+use crate::map::{BOX_ON_GOAL_COLOR, SUNK_BOX_COLOR};
+use crate::input::ActionState;
+use crate::replay::Replay;
+use crate::history::*;
 
 pub fn animate_sprite(
     time: Res<Time>,
@@ -22,138 +33,157 @@ pub fn animate_sprite(
     }
 }
 
-pub fn entity_update(
-    move_timer_query: Query<&MoveTimer>, 
-    mut query: Query<(&mut Transform, &WorldPosition)>) {
+// Moves an entity to another tile, keeping the tile storage and its
+// positions in step with the board
+pub fn move_entity(
+    map_tiles: &mut TileStorage,
+    entity: Entity,
+    tile_pos: &mut TilePos,
+    world_pos: &mut WorldPosition,
+    index: usize) {
+
+    let width = map_tiles.size.width;
+    let new_pos = TilePos::new(index as i32 % width, index as i32 / width, width);
+    map_tiles.move_tile(entity, tile_pos.index, new_pos.index);
+    world_pos.x += (new_pos.x - tile_pos.x) as f32 * 8.;
+    world_pos.y -= (new_pos.y - tile_pos.y) as f32 * 8.;
+    *tile_pos = new_pos;
+}
 
-    // let delta_seconds = time.delta_seconds();
-    let MoveTimer(timer) = move_timer_query.get_single().unwrap();
-    let progress = timer.percent();
+// Swings exits, gates and locked doors open or shut as the board has them,
+// hides the keys that were picked up, lights up boxes on goals and sinks
+// boxes into the holes they filled. Sunk boxes sit below the player so it can
+// walk over them.
+#[allow(clippy::type_complexity)]
+pub fn update_tiles(
+    board_query: Query<Ref<SokobanBoard>>,
+    // Boxes are marked sunk by commands, which land after the move itself
+    sunk_query: Query<(), Added<Sunk>>,
+    mut raised: RemovedComponents<Sunk>,
+    mut tile_query: Query<(&TilePos, &BlockType, &mut Transform, &mut TextureAtlasSprite,
+        Option<&mut DoorSwing>, Option<&Sunk>)>,
+    mut key_query: Query<(&TilePos, &mut Visibility), With<Key>>) {
+
+    let Ok(board) = board_query.get_single() else { return };
+    let raised = raised.iter().count() > 0;
+    if !board.is_changed() && sunk_query.is_empty() && !raised {
+        return;
+    }
+    for (tile_pos, block_type, mut transform, mut sprite, swing, sunk) in &mut tile_query {
+        match block_type {
+            BlockType::Door | BlockType::Gate | BlockType::Lock => {
+                if let Some(mut swing) = swing {
+                    let open = board.is_open(tile_pos.index);
+                    if swing.open != open {
+                        swing.open = open;
+                    }
+                }
+            },
+            BlockType::Box => {
+                let sunk = sunk.is_some();
+                let on_goal = board.goals.contains(&tile_pos.index)
+                    || matches!(board.tiles[tile_pos.index], Tile::Switch(_));
+                sprite.color = if sunk {
+                    SUNK_BOX_COLOR
+                } else if on_goal {
+                    BOX_ON_GOAL_COLOR
+                } else {
+                    Color::WHITE
+                };
+                transform.translation.z = if sunk { 1. } else { 3. };
+            },
+            BlockType::Wall => {},
+        }
+    }
+    for (tile_pos, mut visibility) in &mut key_query {
+        *visibility = if board.keys.contains(&tile_pos.index) { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
 
-    for (mut transform, world_pos) in &mut query {
+// Plays the doors' swings. Doors only drop below the player once they're all
+// the way open, and close over it straight away.
+pub fn animate_doors(
+    time: Res<Time>,
+    mut door_query: Query<(&mut DoorSwing, &mut Transform, &mut TextureAtlasSprite)>) {
 
-        if timer.just_finished() {
-            transform.translation.x = world_pos.x;
-            transform.translation.y = world_pos.y;
-
-        } else if progress <= 1. {
-            let delta = Vec3::new(
-                (world_pos.x - transform.translation.x) * progress,
-                (world_pos.y - transform.translation.y) * progress, 0.);
-            transform.translation += delta;
+    let step = time.delta_seconds() / DOOR_SECONDS;
+    for (mut swing, mut transform, mut sprite) in &mut door_query {
+        let target = if swing.open { 1. } else { 0. };
+        if swing.progress == target {
+            continue;
         }
+        swing.progress = if swing.open { (swing.progress + step).min(1.) } else { (swing.progress - step).max(0.) };
+        sprite.index = swing.frame();
+        transform.translation.z = if swing.progress >= 1. { 2. } else { 3. };
     }
 }
 
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn player_move(
+    mut commands: Commands,
     time: Res<Time>,
-    keyboard_input: Res<Input<KeyCode>>,
-    triggers: Query<&TriggerIndices>,
-    door_index_query: Query<&DoorIndex>,
+    actions: Res<ActionState>,
+    replay: Option<Res<Replay>>,
+    mut history: ResMut<MoveHistory>,
+    mut autoplay: ResMut<Autoplay>,
+    mut stats: ResMut<LevelStats>,
+    mut sounds: EventWriter<PlaySound>,
     mut player: Query<(Entity, &mut WorldPosition, &mut TilePos, 
         &mut MoveTimer, &mut AnimationIndices, &mut TextureAtlasSprite), With<Player>>,
-    mut blocking_tiles_query: Query<
-        (Entity, &mut TilePos, &mut Transform, Option<&mut WorldPosition>, 
-         &mut TextureAtlasSprite, &BlockType), Without<Player>>,
+    mut blocking_tiles_query: Query<(&mut TilePos, &mut WorldPosition), Without<Player>>,
+    mut board_query: Query<&mut SokobanBoard>,
     mut map_tiles_query: Query<&mut TileStorage>) {
 
 
-    for (player_entity, mut world_pos, mut tile_pos, mut move_cooldown, 
+    // Replays keep the controls to themselves, the arrows step through them
+    let controls = replay.is_none();
+
+    // Taking over the controls stops any hint or solution being played
+    if controls && actions.move_just_pressed().is_some() {
+        autoplay.clear();
+    }
+
+    for (player_entity, mut world_pos, mut player_pos, mut move_cooldown, 
         mut anim_indices, mut sprite) in &mut player {
 
         if move_cooldown.tick(time.delta()).finished() {
+            let mut board = board_query.single_mut();
             let mut map_tiles: Mut<TileStorage> = map_tiles_query.single_mut();
 
-            anim_indices.first = 0;
-            anim_indices.last = 7;
-
-            let mut movement = Vec3::ZERO;
-            let mut dx: i32 = 0;
-            let mut dy: i32 = 0;
-            if keyboard_input.pressed(KeyCode::Left) {
-                dx -= 1;
-                movement.x = -1. * 8.;
-            } else if keyboard_input.pressed(KeyCode::Up) {
-                dy -= 1;
-                movement.y = 1. * 8.;
-            } else if keyboard_input.pressed(KeyCode::Right) {
-                dx += 1;
-                movement.x = 1. * 8.;
-            } else if keyboard_input.pressed(KeyCode::Down) {
-                dy += 1;
-                movement.y = -1. * 8.;
-            } else {
-                continue;
-            }
+            *anim_indices = AnimationIndices::IDLE;
 
-            let new_pos = tile_pos.add_and_clone(dx, dy, map_tiles.size.width);
+            let Some(direction) = autoplay.steps.pop_front()
+                .or_else(|| actions.move_pressed().filter(|_| controls))
+                else { continue };
 
             // Flip sprite depending on x coord direction
-            sprite.flip_x = dx < 0;
-
-            // Block if new position is out of bounds
-            if new_pos.x < 0 || new_pos.x >= map_tiles.size.width 
-                || new_pos.y < 0 || new_pos.y >= map_tiles.size.height {
-                return;
-            }
-
-            if let Some(blocking_entity) = map_tiles.move_tile(
-                player_entity, tile_pos.index, new_pos.index) {
-
-                let (box_entity, mut tile_pos, _, maybe_world_pos, _, block_type)
-                    = blocking_tiles_query.get_mut(blocking_entity).unwrap();
-
-                if matches!(block_type, BlockType::Box) {
-                    let new_pos = tile_pos.add_and_clone(dx, dy, map_tiles.size.width);
-
-                    if let None = map_tiles.move_tile(
-                        box_entity, tile_pos.index, new_pos.index) {
-
-                        *tile_pos = new_pos;
-                        let mut world_pos = maybe_world_pos.unwrap();
-                        world_pos.x += movement.x;
-                        world_pos.y += movement.y;
+            sprite.flip_x = direction == Direction::Left;
+
+            let outcome = board.try_move(direction);
+            sounds.send_batch(move_sounds(&board, outcome).into_iter().map(PlaySound));
+            match outcome {
+                MoveOutcome::Blocked => {},
+                MoveOutcome::Walked { to, .. } => {
+                    move_entity(&mut map_tiles, player_entity, &mut player_pos, &mut world_pos, to);
+                    *anim_indices = AnimationIndices::WALK;
+                },
+                MoveOutcome::Pushed { box_from, box_to, filled_hole, .. } => {
+                    // The box first, to make room for the player
+                    let box_entity = map_tiles.tiles[box_from].unwrap();
+                    let (mut box_pos, mut box_world_pos)
+                        = blocking_tiles_query.get_mut(box_entity).unwrap();
+                    move_entity(&mut map_tiles, box_entity, &mut box_pos, &mut box_world_pos, box_to);
+                    if filled_hole {
+                        map_tiles.sink(&mut commands, box_to);
                     }
-
-                    // Check if all triggers are activated
-                    let mut win = true;
-                    let TriggerIndices(triggers) = triggers.single();
-                    for trigger_index in triggers {
-                        if let Some(entity) = map_tiles.tiles[*trigger_index] {
-                            match blocking_tiles_query.get(entity) {
-                                Err(_) => {
-                                    win = false;
-                                    break;
-                                },
-                                Ok((_, _, _, _, _, block_type)) => {
-                                    win = matches!(block_type, BlockType::Box);
-                                }
-                            }
-                        } else {
-                            win = false;
-                            break;
-                        }
-                    }
-                    if win {
-                        let DoorIndex(door_index) = door_index_query.single();
-                        if let Some(door_entity) = map_tiles.tiles[*door_index] {
-                            let (_, _, mut transform, _, mut texture, block_type)
-                                = blocking_tiles_query.get_mut(door_entity).unwrap();
-                            if matches!(block_type, BlockType::Door) {
-                                texture.index = 5; // Switch to opened door sprite
-                                    transform.translation.z -= 1.;
-                                map_tiles.tiles[*door_index] = None;
-                            }
-                        }
-                    }
-                }
-            } else {
-                *tile_pos = new_pos;
-                sprite.index = 8;
-                anim_indices.first = 8;
-                anim_indices.last = 11;
-                world_pos.x += movement.x;
-                world_pos.y += movement.y;
+                    move_entity(&mut map_tiles, player_entity, &mut player_pos, &mut world_pos, box_from);
+                    *anim_indices = AnimationIndices::PUSH;
+                },
+            }
+            if outcome != MoveOutcome::Blocked {
+                sprite.index = anim_indices.first;
+                stats.count(outcome);
+                history.record(MoveRecord { outcome });
             }
 
             move_cooldown.reset();
@@ -164,13 +194,17 @@ pub fn player_move(
 
 pub fn win_condition(
     mut next_state: ResMut<NextState<GameState>>, 
-    player_query: Query<(&TilePos, &MoveTimer), With<Player>>,
-    door_index_query: Query<&DoorIndex>,
+    mut stats: ResMut<LevelStats>,
+    player_query: Query<&MoveTimer, With<Player>>,
+    board_query: Query<&SokobanBoard>,
     ) {
 
-    let DoorIndex(door_index) = door_index_query.get_single().unwrap();
-    for (tile_pos, move_cooldown) in &player_query {
-        if tile_pos.index == *door_index && move_cooldown.finished() {
+    for move_cooldown in &player_query {
+        if !move_cooldown.finished() {
+            continue;
+        }
+        if board_query.get_single().is_ok_and(SokobanBoard::is_solved) {
+            stats.solved = true;
             next_state.set(GameState::NextLevel);
         }
     }
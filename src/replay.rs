@@ -0,0 +1,317 @@
+use std::fmt;
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::board::{MoveOutcome, SokobanBoard};
+// Explicitly, as the bevy prelude has a UI `Direction` too
+use crate::components::{Direction, MoveTimer, Player, MOVE_SECONDS};
+use crate::hint::Autoplay;
+use crate::input::{Action, ActionState};
+use crate::GameState;
+
+// Replay speeds, cycled through with up and down
+pub const SPEEDS: [f32; 5] = [0.5, 1., 2., 4., 8.];
+
+// Character positions and move numbers both start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LurdError {
+    UnknownCharacter { character: char, position: usize },
+    // A lowercase walk that pushes a box, or an uppercase push that doesn't
+    WrongCase { character: char, step: usize },
+    Blocked { character: char, step: usize },
+}
+
+impl fmt::Display for LurdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::UnknownCharacter { character, position } =>
+                write!(f, "unexpected '{}' at position {} of the LURD string", character, position),
+            Self::WrongCase { character, step } => {
+                let outcome = if character.is_ascii_uppercase() { "doesn't push a box" } else { "pushes a box" };
+                write!(f, "move {} of the LURD string, '{}', {}", step, character, outcome)
+            },
+            Self::Blocked { character, step } =>
+                write!(f, "move {} of the LURD string, '{}', is blocked", step, character),
+        }
+    }
+}
+
+impl std::error::Error for LurdError {}
+
+fn lurd_char(direction: Direction, push: bool) -> char {
+    let c = match direction {
+        Direction::Left => 'l',
+        Direction::Up => 'u',
+        Direction::Right => 'r',
+        Direction::Down => 'd',
+    };
+    if push { c.to_ascii_uppercase() } else { c }
+}
+
+// The direction of a move, and whether it was a push
+fn outcome_move(outcome: MoveOutcome, width: i32) -> Option<(Direction, bool)> {
+    let (from, to, push) = match outcome {
+        MoveOutcome::Blocked => return None,
+        MoveOutcome::Walked { from, to, .. } => (from, to, false),
+        MoveOutcome::Pushed { from, box_from, .. } => (from, box_from, true),
+    };
+    // Slides over ice can cover several tiles in one move
+    let delta = to as i32 - from as i32;
+    let direction = if delta <= -width {
+        Direction::Up
+    } else if delta >= width {
+        Direction::Down
+    } else if delta < 0 {
+        Direction::Left
+    } else {
+        Direction::Right
+    };
+    Some((direction, push))
+}
+
+/// Writes moves down in LURD notation, lowercase for walks and uppercase for
+/// pushes.
+pub fn record_lurd(moves: &[MoveOutcome], width: i32) -> String {
+    moves.iter()
+        .filter_map(|outcome| outcome_move(*outcome, width))
+        .map(|(direction, push)| lurd_char(direction, push))
+        .collect()
+}
+
+// Moves in LURD notation, with whitespace ignored
+pub fn parse_lurd(lurd: &str) -> Result<Vec<(Direction, bool)>, LurdError> {
+    lurd.chars().enumerate()
+        .filter(|(_, c)| !c.is_whitespace())
+        .map(|(index, c)| {
+            let direction = match c.to_ascii_lowercase() {
+                'l' => Direction::Left,
+                'u' => Direction::Up,
+                'r' => Direction::Right,
+                'd' => Direction::Down,
+                _ => return Err(LurdError::UnknownCharacter { character: c, position: index + 1 }),
+            };
+            Ok((direction, c.is_ascii_uppercase()))
+        })
+        .collect()
+}
+
+// Checks that a LURD move played out as written, uppercase moves pushing a
+// box and lowercase ones walking
+pub fn check_step(step: usize, (direction, push): (Direction, bool), outcome: MoveOutcome)
+    -> Result<(), LurdError> {
+
+    let character = lurd_char(direction, push);
+    match outcome {
+        MoveOutcome::Blocked => Err(LurdError::Blocked { character, step }),
+        MoveOutcome::Walked { .. } if push => Err(LurdError::WrongCase { character, step }),
+        MoveOutcome::Pushed { .. } if !push => Err(LurdError::WrongCase { character, step }),
+        _ => Ok(()),
+    }
+}
+
+// A solution being played back on the current level
+#[derive(Resource)]
+pub struct Replay {
+    pub steps: Vec<(Direction, bool)>,
+    pub next: usize,
+    pub paused: bool,
+    pub speed: usize,
+    // Set on the first move that doesn't play out as written, which ends the replay
+    pub error: Option<LurdError>,
+}
+
+impl Replay {
+    pub fn new(lurd: &str) -> Result<Self, LurdError> {
+        Ok(Self {
+            steps: parse_lurd(lurd)?,
+            next: 0,
+            paused: false,
+            speed: 1,
+            error: None,
+        })
+    }
+}
+
+#[derive(Component)]
+pub struct ReplayBanner;
+
+// Replays a solution from the clipboard, on the level being played
+pub fn paste_replay(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    mut next_state: ResMut<NextState<GameState>>) {
+
+    if !actions.just_pressed(Action::Replay) {
+        return;
+    }
+    let text = match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+        Ok(text) => text,
+        Err(err) => return warn!("Couldn't read the clipboard: {}", err),
+    };
+    match Replay::new(&text) {
+        Ok(replay) => {
+            commands.insert_resource(replay);
+            next_state.set(GameState::Resetting);
+        },
+        Err(err) => warn!("Couldn't replay the clipboard: {}", err),
+    }
+}
+
+// Replays start over with the level
+pub fn rewind_replay(replay: Option<ResMut<Replay>>) {
+    if let Some(mut replay) = replay {
+        replay.next = 0;
+    }
+}
+
+pub fn stop_replay(mut commands: Commands) {
+    commands.remove_resource::<Replay>();
+}
+
+// Feeds the replay to `player_move` one step at a time, so it's animated
+// like any other move
+pub fn drive_replay(
+    mut commands: Commands,
+    actions: Res<ActionState>,
+    replay: Option<ResMut<Replay>>,
+    mut autoplay: ResMut<Autoplay>,
+    board_query: Query<&SokobanBoard>,
+    mut player_query: Query<&mut MoveTimer, With<Player>>) {
+
+    let Some(mut replay) = replay else { return };
+    let (Ok(mut move_cooldown), Ok(board)) = (player_query.get_single_mut(), board_query.get_single())
+        else { return };
+
+    if actions.just_pressed(Action::Confirm) {
+        replay.paused = !replay.paused;
+    }
+    if actions.just_pressed(Action::Move(Direction::Up)) {
+        replay.speed = (replay.speed + 1).min(SPEEDS.len() - 1);
+    } else if actions.just_pressed(Action::Move(Direction::Down)) {
+        replay.speed = replay.speed.saturating_sub(1);
+    }
+    let step = replay.paused && actions.just_pressed(Action::Move(Direction::Right));
+
+    if replay.error.is_some() || replay.next >= replay.steps.len() {
+        // Done, hand the controls back unless the level is about to be left.
+        // `save_progress` still needs to know it was a replay then.
+        if move_cooldown.finished() && !board.is_solved() {
+            move_cooldown.set_duration(Duration::from_secs_f32(MOVE_SECONDS));
+            commands.remove_resource::<Replay>();
+        }
+        return;
+    }
+    move_cooldown.set_duration(Duration::from_secs_f32(MOVE_SECONDS / SPEEDS[replay.speed]));
+    if autoplay.steps.is_empty() && (!replay.paused || step) {
+        let next_move = replay.steps[replay.next];
+        // Tried out on a copy first, as `player_move` plays it next
+        if let Err(err) = check_step(replay.next + 1, next_move, board.clone().try_move(next_move.0)) {
+            warn!("Stopped the replay: {}", err);
+            replay.error = Some(err);
+            return;
+        }
+        autoplay.steps.push_back(next_move.0);
+        replay.next += 1;
+    }
+}
+
+pub fn show_replay_banner(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    replay: Option<Res<Replay>>,
+    mut banner_query: Query<(Entity, &mut Text), With<ReplayBanner>>) {
+
+    let Some(replay) = replay else {
+        for (entity, _) in &banner_query {
+            commands.entity(entity).despawn();
+        }
+        return;
+    };
+    if !replay.is_changed() && !banner_query.is_empty() {
+        return;
+    }
+
+    let text = format!("Replay {}x {}/{}{}", SPEEDS[replay.speed], replay.next, replay.steps.len(),
+        if replay.paused { " - paused" } else { "" });
+    if let Ok((_, mut banner)) = banner_query.get_single_mut() {
+        banner.sections[0].value = text;
+        return;
+    }
+    commands.spawn((
+        TextBundle::from_section(
+            text,
+            TextStyle {
+                font: asset_server.load("Minimal3x5.ttf"),
+                font_size: 5.0,
+                color: Color::AQUAMARINE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                right: Val::Px(2.),
+                bottom: Val::Px(2.),
+                ..default()
+            },
+            ..default()
+        }),
+        ReplayBanner,
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::load;
+
+    #[test]
+    fn lurd_strings_are_parsed_with_pushes_in_uppercase() {
+        assert_eq!(parse_lurd("rU d\nL").unwrap(), vec![(Direction::Right, false),
+            (Direction::Up, true), (Direction::Down, false), (Direction::Left, true)]);
+        assert_eq!(parse_lurd("rrx"), Err(LurdError::UnknownCharacter { character: 'x', position: 3 }));
+    }
+
+    #[test]
+    fn pushes_are_recorded_in_uppercase() {
+        let mut board = load("#######\n#@ $ .#\n#     #\n#######");
+        let moves: Vec<_> = [Direction::Right, Direction::Right, Direction::Right, Direction::Down,
+            Direction::Left].into_iter().map(|direction| board.try_move(direction)).collect();
+        assert_eq!(record_lurd(&moves, board.size.width), "rRRdl");
+        // Blocked moves are left out
+        let moves = [MoveOutcome::Blocked, moves[0]];
+        assert_eq!(record_lurd(&moves, board.size.width), "r");
+    }
+
+    #[test]
+    fn recorded_solutions_replay_to_the_same_position() {
+        let map = "#######\n#@ $ .#\n#  $ .#\n#######";
+        let mut played = load(map);
+        let moves: Vec<_> = [Direction::Right, Direction::Right, Direction::Right, Direction::Left,
+            Direction::Left, Direction::Down, Direction::Right, Direction::Right]
+            .into_iter().map(|direction| played.try_move(direction)).collect();
+
+        let lurd = record_lurd(&moves, played.size.width);
+        let mut replayed = load(map);
+        for (index, step) in parse_lurd(&lurd).unwrap().into_iter().enumerate() {
+            assert_eq!(check_step(index + 1, step, replayed.try_move(step.0)), Ok(()));
+        }
+        assert_eq!(lurd, "rRRlldRR");
+        assert!(replayed.triggers_filled());
+        assert_eq!(replayed.boxes, played.boxes);
+        assert_eq!(replayed.player, played.player);
+    }
+
+    #[test]
+    fn moves_must_push_exactly_when_written_in_uppercase() {
+        let mut board = load("#######\n#@ $ .#\n#######");
+        let walk = board.try_move(Direction::Right);
+        let push = board.try_move(Direction::Right);
+        assert_eq!(check_step(1, (Direction::Right, true), walk),
+            Err(LurdError::WrongCase { character: 'R', step: 1 }));
+        assert_eq!(check_step(2, (Direction::Right, false), push),
+            Err(LurdError::WrongCase { character: 'r', step: 2 }));
+        assert_eq!(check_step(3, (Direction::Up, false), board.try_move(Direction::Up)),
+            Err(LurdError::Blocked { character: 'u', step: 3 }));
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+use bevy::prelude::*;
+use directories::ProjectDirs;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::board::SokobanBoard;
+use crate::editor::Playtest;
+use crate::history::MoveHistory;
+use crate::hud::LevelStats;
+use crate::replay::{record_lurd, Replay};
+use crate::{GameLevel, LevelPackPath};
+
+// Overrides where progress is saved, e.g. to keep tests away from the real save
+pub const SAVE_PATH_VAR: &str = "BEVY_SOKOBAN_SAVE";
+
+// Best results for a level, each kept on its own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BestScore {
+    pub moves: u32,
+    pub pushes: u32,
+    pub time: Duration,
+    // The solution with the fewest moves, in LURD notation
+    #[serde(default)]
+    pub solution: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PackProgress {
+    // Levels up to and including this one can be played
+    pub unlocked: usize,
+    pub best: BTreeMap<usize, BestScore>,
+}
+
+impl PackProgress {
+    pub fn record(&mut self, level: usize, stats: &LevelStats, solution: String) {
+        self.unlocked = self.unlocked.max(level + 1);
+        let score = BestScore {
+            moves: stats.moves,
+            pushes: stats.pushes,
+            time: stats.elapsed,
+            solution,
+        };
+        self.best.entry(level)
+            .and_modify(|best| {
+                if score.moves < best.moves {
+                    best.solution = score.solution.clone();
+                }
+                best.moves = best.moves.min(score.moves);
+                best.pushes = best.pushes.min(score.pushes);
+                best.time = best.time.min(score.time);
+            })
+            .or_insert(score);
+    }
+}
+
+// Progress through every level pack played so far, keyed by pack path
+#[derive(Resource, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SaveData {
+    pub packs: BTreeMap<String, PackProgress>,
+}
+
+// Where `SaveData` is written, if there's anywhere to write it
+#[derive(Resource)]
+pub struct SavePath(pub Option<PathBuf>);
+
+impl SavePath {
+    pub fn from_env() -> Self {
+        let path = std::env::var_os(SAVE_PATH_VAR).map(PathBuf::from).or_else(||
+            ProjectDirs::from("", "", "bevy_sokoban")
+                .map(|dirs| dirs.data_dir().join("save.ron")));
+        Self(path)
+    }
+}
+
+// Reads a RON file. A missing file is a fresh start, a broken one is
+// reported and ignored.
+pub fn load_ron<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match fs::read_to_string(path) {
+        Ok(contents) => ron::from_str(&contents).unwrap_or_else(|err| {
+            warn!("Ignoring unreadable file {}: {}", path.display(), err);
+            T::default()
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => T::default(),
+        Err(err) => {
+            warn!("Couldn't read {}: {}", path.display(), err);
+            T::default()
+        },
+    }
+}
+
+pub fn save_ron<T: Serialize>(value: &T, path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents = ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    fs::write(path, contents)
+}
+
+impl SaveData {
+    pub fn load(path: &Path) -> Self {
+        load_ron(path)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        save_ron(self, path)
+    }
+
+    pub fn pack(&self, pack_path: &str) -> Option<&PackProgress> {
+        self.packs.get(pack_path)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn save_progress(
+    stats: Res<LevelStats>,
+    history: Res<MoveHistory>,
+    board_query: Query<&SokobanBoard>,
+    replay: Option<Res<Replay>>,
+    playtest: Option<Res<Playtest>>,
+    game_level: Res<GameLevel>,
+    level_pack_path: Res<LevelPackPath>,
+    save_path: Res<SavePath>,
+    mut save_data: ResMut<SaveData>) {
+
+    // Watching a replay isn't solving the level, and playtests aren't part of the pack
+    if replay.is_some() || playtest.is_some() {
+        return;
+    }
+    let Ok(board) = board_query.get_single() else { return };
+    let moves: Vec<_> = history.undo.iter().map(|record| record.outcome).collect();
+    save_data.packs.entry(level_pack_path.0.clone()).or_default()
+        .record(game_level.0, &stats, record_lurd(&moves, board.size.width));
+
+    if let Some(path) = &save_path.0 {
+        if let Err(err) = save_data.save(path) {
+            warn!("Couldn't save progress to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(moves: u32, pushes: u32, seconds: u64) -> LevelStats {
+        LevelStats { moves, pushes, elapsed: Duration::from_secs(seconds), solved: true }
+    }
+
+    #[test]
+    fn solving_a_level_unlocks_the_next_one() {
+        let mut progress = PackProgress::default();
+        progress.record(0, &stats(10, 2, 5), String::new());
+        assert_eq!(progress.unlocked, 1);
+        // Replaying an earlier level doesn't lock anything again
+        progress.record(3, &stats(10, 2, 5), String::new());
+        progress.record(1, &stats(10, 2, 5), String::new());
+        assert_eq!(progress.unlocked, 4);
+    }
+
+    #[test]
+    fn best_scores_are_kept_separately() {
+        let mut progress = PackProgress::default();
+        progress.record(0, &stats(20, 4, 30), String::from("rRuL"));
+        progress.record(0, &stats(25, 3, 10), String::from("rRulL"));
+        // The solution goes with the fewest moves
+        assert_eq!(progress.best[&0], BestScore {
+            moves: 20, pushes: 3, time: Duration::from_secs(10), solution: String::from("rRuL") });
+    }
+
+    #[test]
+    fn saves_round_trip_through_the_file() {
+        let path = std::env::temp_dir()
+            .join(format!("bevy_sokoban_test_{}", std::process::id()))
+            .join("save.ron");
+        assert_eq!(SaveData::load(&path), SaveData::default());
+
+        let mut data = SaveData::default();
+        data.packs.entry("levels/default.xsb".into()).or_default()
+            .record(2, &stats(7, 1, 3), String::from("rRdL"));
+        data.save(&path).unwrap();
+        assert_eq!(SaveData::load(&path), data);
+
+        fs::write(&path, "not a save").unwrap();
+        assert_eq!(SaveData::load(&path), SaveData::default());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
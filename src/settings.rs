@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::input::Bindings;
+use crate::save::save_ron;
+
+// Overrides where the settings are read from and written to
+pub const SETTINGS_PATH_VAR: &str = "BEVY_SOKOBAN_SETTINGS";
+
+// Player preferences, changed from the settings menu or by editing the file
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub show_hud: bool,
+    pub deadlock_warnings: bool,
+    // From 0 for silence to 1 for full volume
+    pub music_volume: f32,
+    pub sound_volume: f32,
+    // Size of the window in pixels, as it was last left when not fullscreen
+    pub window_size: (u32, u32),
+    pub fullscreen: bool,
+    pub bindings: Bindings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            show_hud: true,
+            deadlock_warnings: true,
+            music_volume: 0.5,
+            sound_volume: 0.8,
+            window_size: (640, 400),
+            fullscreen: false,
+            bindings: Bindings::default(),
+        }
+    }
+}
+
+// Where `Settings` are kept, if there's anywhere to keep them
+#[derive(Resource)]
+pub struct SettingsPath(pub Option<PathBuf>);
+
+impl SettingsPath {
+    pub fn from_env() -> Self {
+        let path = std::env::var_os(SETTINGS_PATH_VAR).map(PathBuf::from).or_else(||
+            ProjectDirs::from("", "", "bevy_sokoban")
+                .map(|dirs| dirs.config_dir().join("settings.ron")));
+        Self(path)
+    }
+}
+
+// Writes the settings back whenever they're changed in game
+pub fn save_settings(settings: Res<Settings>, settings_path: Res<SettingsPath>) {
+    if !settings.is_changed() || settings.is_added() {
+        return;
+    }
+    if let Some(path) = &settings_path.0 {
+        if let Err(err) = save_ron(&*settings, path) {
+            warn!("Couldn't save settings to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip_through_ron() {
+        let settings = Settings { show_hud: false, ..default() };
+        let text = ron::ser::to_string_pretty(&settings, default()).unwrap();
+        assert_eq!(ron::from_str::<Settings>(&text).unwrap(), settings);
+    }
+
+    #[test]
+    fn missing_fields_keep_their_defaults() {
+        let settings: Settings = ron::from_str("(show_hud: false)").unwrap();
+        assert!(!settings.show_hud);
+        assert_eq!(settings.bindings, Bindings::default());
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::cmp::Reverse;
+
+use crate::components::Direction;
+use crate::board::SokobanBoard;
+use crate::levels::Tile;
+
+// Stop searching past this many positions instead of freezing the game
+pub const MAX_STATES: usize = 500_000;
+// Corral checks run after every push, so they have to give up a lot sooner
+const CORRAL_STATES: usize = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SolveError {
+    // Every reachable position was tried
+    Unsolvable,
+    // The search gave up before finding out
+    TooManyStates,
+    // There's no solution without opening locked doors, which the search doesn't do
+    NeedsKeys,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlockKind {
+    // A box sits where it can never be pushed to a goal from
+    DeadSquare,
+    // A box off goal can't be moved along either axis anymore
+    Frozen,
+    // Boxes close off an area the player can't solve from the outside
+    Corral,
+}
+
+// Why a box can't be pushed where it was asked to go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushPlanError {
+    // There's no way to push it there
+    Blocked,
+    // It could get there, but never to a goal after that
+    DeadSquare,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Push {
+    pub box_index: usize,
+    pub direction: Direction,
+}
+
+// Plain grid model of a level, independent from the ECS world
+#[derive(Debug, Clone)]
+pub struct SolverGrid {
+    pub width: i32,
+    pub height: i32,
+    pub walls: Vec<bool>,
+    pub goals: Vec<usize>,
+    pub boxes: Vec<usize>,
+    pub player: usize,
+    // Gates, unlocked doors and keys, which boxes are kept out of
+    pub player_only: Vec<bool>,
+    // Gates and switches with their letters, gates close as boxes leave the switches
+    pub gates: Vec<(usize, u8)>,
+    pub switches: Vec<(usize, u8)>,
+    // Whether keys could still open some of the locked doors
+    pub needs_keys: bool,
+    // Ice, which boxes and the player slide over, one-way floors, and the
+    // holes not filled yet. A box in a hole is gone and the hole is floor after.
+    pub ice: Vec<bool>,
+    pub one_way: Vec<Option<Direction>>,
+    pub holes: Vec<usize>,
+}
+
+impl SolverGrid {
+    // Closed and locked doors count as walls, open ones as floor
+    pub fn from_board(board: &SokobanBoard) -> Self {
+        let locked = |index: usize| board.tiles[index] == Tile::Lock && !board.unlocked.contains(&index);
+        let lettered = |wanted: fn(Tile) -> Option<u8>| board.tiles.iter().enumerate()
+            .filter_map(|(index, tile)| wanted(*tile).map(|letter| (index, letter)))
+            .collect();
+        Self {
+            width: board.size.width,
+            height: board.size.height,
+            walls: board.tiles.iter().enumerate().map(|(index, tile)| match tile {
+                Tile::Floor | Tile::Switch(_) | Tile::Gate(_)
+                    | Tile::Ice | Tile::OneWay(_) | Tile::Hole => false,
+                Tile::Door => !board.door_open,
+                Tile::Lock => locked(index),
+                Tile::Wall | Tile::Outside => true,
+            }).collect(),
+            goals: board.goals.clone(),
+            boxes: board.boxes.clone(),
+            player: board.player,
+            player_only: board.tiles.iter().enumerate()
+                .map(|(index, tile)| matches!(tile, Tile::Gate(_) | Tile::Lock) || board.keys.contains(&index))
+                .collect(),
+            gates: lettered(|tile| match tile { Tile::Gate(letter) => Some(letter), _ => None }),
+            switches: lettered(|tile| match tile { Tile::Switch(letter) => Some(letter), _ => None }),
+            needs_keys: (0..board.tiles.len()).any(locked)
+                && (board.held_keys > 0 || !board.keys.is_empty()),
+            ice: board.tiles.iter().map(|tile| *tile == Tile::Ice).collect(),
+            one_way: board.tiles.iter().map(|tile| match tile {
+                Tile::OneWay(direction) => Some(*direction),
+                _ => None,
+            }).collect(),
+            holes: (0..board.tiles.len()).filter(|&index| board.tiles[index] == Tile::Hole).collect(),
+        }
+    }
+
+    // The neighbouring tile in the given direction, unless it's a wall or off the map
+    pub fn step(&self, index: usize, direction: Direction) -> Option<usize> {
+        let (dx, dy) = direction.delta();
+        let x = index as i32 % self.width + dx;
+        let y = index as i32 / self.width + dy;
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            return None;
+        }
+        let next = (x + y * self.width) as usize;
+        (!self.walls[next]).then_some(next)
+    }
+
+    // Same as `step`, but only onto tiles that may be entered going that way
+    fn enter(&self, index: usize, direction: Direction) -> Option<usize> {
+        self.step(index, direction)
+            .filter(|next| self.one_way[*next].is_none_or(|way| way == direction))
+    }
+
+    // Same as `enter`, for a box being pushed
+    pub fn box_step(&self, index: usize, direction: Direction) -> Option<usize> {
+        self.enter(index, direction).filter(|next| !self.player_only[*next])
+    }
+
+    // The player steps into the tile a box is pushed from, which a one-way
+    // floor may not allow
+    fn can_follow(&self, box_index: usize, direction: Direction) -> bool {
+        self.one_way[box_index].is_none_or(|way| way == direction)
+    }
+
+    // Where a push sends a box, sliding over ice until something stops it or
+    // it drops into a hole. Filled holes are floor like any other.
+    fn box_landing(&self, boxes: &[usize], index: usize, direction: Direction) -> Option<usize> {
+        let mut to = self.box_step(index, direction).filter(|next| !boxes.contains(next))?;
+        while self.ice[to] {
+            match self.box_step(to, direction).filter(|next| !boxes.contains(next)) {
+                Some(next) => to = next,
+                None => break,
+            }
+        }
+        Some(to)
+    }
+
+    // Where a step takes the player, sliding over ice the same way. Holes
+    // still open can't be walked into.
+    fn player_step(&self, (boxes, holes): (&[usize], &[usize]), closed: &[usize], index: usize,
+        direction: Direction) -> Option<usize> {
+
+        let open = |next: &usize| !boxes.contains(next) && !holes.contains(next) && !closed.contains(next);
+        let mut to = self.enter(index, direction).filter(open)?;
+        while self.ice[to] {
+            match self.enter(to, direction).filter(open) {
+                Some(next) => to = next,
+                None => break,
+            }
+        }
+        Some(to)
+    }
+
+    // Ice and one-way floors make walks one-way, so only the tiles the player
+    // can walk to from anywhere around the same boxes are interchangeable
+    fn has_one_way_walks(&self) -> bool {
+        self.ice.contains(&true) || self.one_way.iter().any(Option::is_some)
+    }
+
+    // The top-left-most tile the player can walk to, standing for all of them
+    fn normalize(&self, boxes: &[usize], holes: &[usize], player: usize) -> usize {
+        if self.has_one_way_walks() {
+            return player;
+        }
+        self.reachable(boxes, holes, player).iter().position(|&seen| seen).unwrap_or(player)
+    }
+
+    // Boxes beyond the number of goals, which are free to end up anywhere
+    fn spare_boxes(&self) -> bool {
+        self.boxes.len() > self.goals.len()
+    }
+
+    // Gates with a switch of their letter that has no box on it
+    fn closed_gates(&self, boxes: &[usize]) -> Vec<usize> {
+        self.gates.iter()
+            .filter(|(_, letter)| self.switches.iter()
+                .any(|(switch, switch_letter)| switch_letter == letter && !boxes.contains(switch)))
+            .map(|(gate, _)| *gate)
+            .collect()
+    }
+
+    // Every tile the player can walk to without pushing, as a mask
+    fn reachable(&self, boxes: &[usize], holes: &[usize], from: usize) -> Vec<bool> {
+        let closed = self.closed_gates(boxes);
+        let mut seen = vec![false; self.walls.len()];
+        let mut stack = vec![from];
+        seen[from] = true;
+        while let Some(index) = stack.pop() {
+            for direction in Direction::ALL {
+                if let Some(next) = self.player_step((boxes, holes), &closed, index, direction) {
+                    if !seen[next] {
+                        seen[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+        }
+        seen
+    }
+
+    /// Tiles from which a box can never be pushed onto any goal.
+    ///
+    /// Found by pulling a box backwards from every goal and hole; whatever
+    /// it can't be pulled to is dead. Ice is never dead, as boxes only slide
+    /// over it.
+    pub fn dead_squares(&self) -> Vec<bool> {
+        let mut alive = vec![false; self.walls.len()];
+        let mut queue: VecDeque<usize> = (0..self.walls.len())
+            .filter(|&index| self.goals.contains(&index) || self.holes.contains(&index) || self.ice[index])
+            .collect();
+        for index in &queue {
+            alive[*index] = true;
+        }
+        while let Some(index) = queue.pop_front() {
+            for direction in Direction::ALL {
+                // The box moves to `next` while the player stands one further away
+                let Some(next) = self.step(index, direction) else { continue };
+                if alive[next] || self.box_step(next, direction.opposite()) != Some(index)
+                    || self.step(next, direction).is_none() {
+                    continue;
+                }
+                alive[next] = true;
+                queue.push_back(next);
+            }
+        }
+        alive.into_iter().zip(&self.walls).map(|(alive, wall)| !alive && !wall).collect()
+    }
+
+    /// Fewest pushes that move a single box to `to`, leaving the other boxes
+    /// where they are. The walks between pushes are left to the caller.
+    pub fn push_path(&self, from: usize, to: usize) -> Result<Vec<Push>, PushPlanError> {
+        if self.walls[to] || self.player_only[to] || (to != from && self.boxes.contains(&to)) {
+            return Err(PushPlanError::Blocked);
+        }
+        if !self.spare_boxes() && self.dead_squares()[to] {
+            return Err(PushPlanError::DeadSquare);
+        }
+
+        let others: Vec<usize> = self.boxes.iter().copied().filter(|index| *index != from).collect();
+        let with_box = |box_index| [others.as_slice(), &[box_index]].concat();
+        let normalize = |box_index, player| self.normalize(&with_box(box_index), &self.holes, player);
+
+        let start = (from, normalize(from, self.player));
+        let mut came_from: HashMap<(usize, usize), ((usize, usize), Push)> = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(state) = queue.pop_front() {
+            let (box_index, player) = state;
+            if box_index == to {
+                let mut pushes = Vec::new();
+                let mut current = state;
+                while current != start {
+                    let (previous, push) = came_from[&current];
+                    pushes.push(push);
+                    current = previous;
+                }
+                pushes.reverse();
+                return Ok(pushes);
+            }
+
+            // A box in a hole is there for good
+            if self.holes.contains(&box_index) {
+                continue;
+            }
+            let reachable = self.reachable(&with_box(box_index), &self.holes, player);
+            for direction in Direction::ALL {
+                let Some(target) = self.box_landing(&others, box_index, direction) else { continue };
+                let Some(behind) = self.step(box_index, direction.opposite()) else { continue };
+                if !reachable[behind] || !self.can_follow(box_index, direction) {
+                    continue;
+                }
+                let next = (target, normalize(target, box_index));
+                if next != start && !came_from.contains_key(&next) {
+                    came_from.insert(next, (state, Push { box_index, direction }));
+                    queue.push_back(next);
+                }
+            }
+        }
+        Err(PushPlanError::Blocked)
+    }
+
+    // Lower bound on the pushes left: every box still has to reach some goal.
+    // Not with ice, where one push can slide a box any distance, or with spare
+    // boxes that don't need to go anywhere.
+    fn estimate(&self, boxes: &[usize]) -> usize {
+        if self.ice.contains(&true) || self.spare_boxes() {
+            return 0;
+        }
+        boxes.iter().map(|&index| {
+            let (x, y) = (index as i32 % self.width, index as i32 / self.width);
+            self.goals.iter().map(|&goal| {
+                let (gx, gy) = (goal as i32 % self.width, goal as i32 / self.width);
+                ((x - gx).abs() + (y - gy).abs()) as usize
+            }).min().unwrap_or(0)
+        }).sum()
+    }
+
+    /// Finds a solution with the fewest pushes, searching A* over box
+    /// positions. Player positions are normalized to the top-left-most tile
+    /// they can walk to, so positions that only differ by walking are merged.
+    /// Boxes that drop into holes leave the position, and the holes they fill
+    /// are floor from then on.
+    ///
+    /// The search stops with `TooManyStates` after `max_states` positions.
+    pub fn solve(&self, max_states: usize) -> Result<Vec<Push>, SolveError> {
+        // Spare boxes may have to be pushed out of the way anywhere
+        let dead = if self.spare_boxes() { vec![false; self.walls.len()] } else { self.dead_squares() };
+
+        let mut start_boxes = self.boxes.clone();
+        start_boxes.sort_unstable();
+        let player = self.normalize(&self.boxes, &self.holes, self.player);
+        let start = (start_boxes, self.holes.clone(), player);
+
+        // Every visited position, with the position and push it was reached from
+        let mut states = vec![(start.clone(), usize::MAX, None)];
+        let mut costs = HashMap::from([(start, 0)]);
+        let mut open = BinaryHeap::from([Reverse((self.estimate(&states[0].0.0), 0, 0))]);
+
+        while let Some(Reverse((_, cost, state))) = open.pop() {
+            let ((boxes, holes, player), _, _) = &states[state];
+            if costs[&states[state].0] < cost {
+                // Already expanded through a cheaper route
+                continue;
+            }
+            // The second also holds when there are more goals than boxes, see
+            // `corral_deadlock`
+            if self.goals.iter().all(|goal| boxes.contains(goal))
+                || boxes.iter().all(|index| self.goals.contains(index)) {
+                let mut pushes = Vec::new();
+                let mut current = state;
+                while let (_, parent, Some(push)) = &states[current] {
+                    pushes.push(*push);
+                    current = *parent;
+                }
+                pushes.reverse();
+                return Ok(pushes);
+            }
+            if states.len() >= max_states {
+                return Err(SolveError::TooManyStates);
+            }
+
+            let (boxes, holes, player) = (boxes.clone(), holes.clone(), *player);
+            let reachable = self.reachable(&boxes, &holes, player);
+            for (slot, &box_index) in boxes.iter().enumerate() {
+                for direction in Direction::ALL {
+                    let Some(target) = self.box_landing(&boxes, box_index, direction) else { continue };
+                    let Some(behind) = self.step(box_index, direction.opposite()) else { continue };
+                    if !reachable[behind] || dead[target] || !self.can_follow(box_index, direction) {
+                        continue;
+                    }
+
+                    let mut next_boxes = boxes.clone();
+                    let mut next_holes = holes.clone();
+                    if holes.contains(&target) {
+                        // Every goal still needs a box
+                        if boxes.len() <= self.goals.len() {
+                            continue;
+                        }
+                        next_boxes.remove(slot);
+                        next_holes.retain(|hole| *hole != target);
+                    } else {
+                        next_boxes[slot] = target;
+                        next_boxes.sort_unstable();
+                    }
+                    let next_player = self.normalize(&next_boxes, &next_holes, box_index);
+                    let next = (next_boxes, next_holes, next_player);
+                    if costs.get(&next).is_some_and(|&known| known <= cost + 1) {
+                        continue;
+                    }
+                    costs.insert(next.clone(), cost + 1);
+                    open.push(Reverse((cost + 1 + self.estimate(&next.0), cost + 1, states.len())));
+                    states.push((next, state, Some(Push { box_index, direction })));
+                }
+            }
+        }
+        Err(if self.needs_keys { SolveError::NeedsKeys } else { SolveError::Unsolvable })
+    }
+
+    /// Checks the position for deadlocks that can be spotted quickly, given
+    /// the dead squares of the level.
+    pub fn deadlock(&self, dead: &[bool]) -> Option<DeadlockKind> {
+        let off_goal = || self.boxes.iter().filter(|index| !self.goals.contains(index));
+        // Spare boxes can be left stuck anywhere
+        let spare = self.boxes.len().saturating_sub(self.goals.len());
+
+        if off_goal().filter(|&&index| dead[index]).count() > spare {
+            return Some(DeadlockKind::DeadSquare);
+        }
+        if off_goal().filter(|&&index| self.frozen(index, dead, &mut Vec::new())).count() > spare {
+            return Some(DeadlockKind::Frozen);
+        }
+        if self.corral_deadlock() {
+            return Some(DeadlockKind::Corral);
+        }
+        None
+    }
+
+    // A box is frozen when it's blocked both horizontally and vertically.
+    // Boxes already looked at count as walls, which breaks cycles.
+    fn frozen(&self, index: usize, dead: &[bool], visited: &mut Vec<usize>) -> bool {
+        visited.push(index);
+        let blocked = |axis: [Direction; 2], visited: &mut Vec<usize>| {
+            // One-way floors don't count, the player may still stand on them
+            let neighbours = axis.map(|direction| self.step(index, direction)
+                .filter(|next| !self.player_only[*next]));
+            // A wall on either side, or nowhere to go but dead squares
+            if neighbours.iter().any(|neighbour| neighbour.is_none_or(|n| visited.contains(&n)))
+                || neighbours.iter().flatten().all(|&n| dead[n]) {
+                return true;
+            }
+            neighbours.into_iter().flatten().any(|n|
+                self.boxes.contains(&n) && self.frozen(n, dead, visited))
+        };
+        blocked([Direction::Left, Direction::Right], visited)
+            && blocked([Direction::Up, Direction::Down], visited)
+    }
+
+    // Areas the player can't walk into are only solvable if the boxes around
+    // them can be moved right. That's checked by solving the level with just
+    // those boxes, as taking boxes away never makes a level harder. Except
+    // with ice, where boxes stop slides, and holes that need filling. Boxes
+    // on switches stay too, taking them away would close their gates.
+    fn corral_deadlock(&self) -> bool {
+        if self.ice.contains(&true) || !self.holes.is_empty() {
+            return false;
+        }
+        let mut seen = self.reachable(&self.boxes, &self.holes, self.player);
+
+        for start in 0..self.walls.len() {
+            if seen[start] || self.walls[start] || self.boxes.contains(&start) {
+                continue;
+            }
+
+            let mut fence = Vec::new();
+            let mut stack = vec![start];
+            seen[start] = true;
+            while let Some(index) = stack.pop() {
+                for direction in Direction::ALL {
+                    let Some(next) = self.step(index, direction) else { continue };
+                    if self.boxes.contains(&next) {
+                        if !fence.contains(&next) {
+                            fence.push(next);
+                        }
+                    } else if !seen[next] {
+                        seen[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+
+            for (switch, _) in &self.switches {
+                if self.boxes.contains(switch) && !fence.contains(switch) {
+                    fence.push(*switch);
+                }
+            }
+            let corral = SolverGrid { boxes: fence, ..self.clone() };
+            if corral.solve(CORRAL_STATES) == Err(SolveError::Unsolvable) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::MoveOutcome;
+    use crate::board::load;
+
+    fn grid(map: &str) -> SolverGrid {
+        SolverGrid::from_board(&load(map))
+    }
+
+    #[test]
+    fn boxes_are_pushed_around_corners() {
+        let grid = grid("#######\n#@    #\n# $   #\n#    .#\n#######");
+        let pushes = grid.push_path(16, 26).unwrap();
+        assert_eq!(pushes.len(), 4);
+        assert_eq!(pushes[0].box_index, 16);
+        let last = pushes.last().unwrap();
+        assert_eq!(grid.step(last.box_index, last.direction), Some(26));
+    }
+
+    #[test]
+    fn other_boxes_stay_in_place() {
+        let grid = grid("#######\n#@$ $.#\n#    .#\n#######");
+        assert_eq!(grid.push_path(9, 10), Ok(vec![Push { box_index: 9, direction: Direction::Right }]));
+        assert_eq!(grid.push_path(9, 12), Err(PushPlanError::Blocked));
+    }
+
+    #[test]
+    fn dead_squares_are_refused() {
+        let grid = grid("######\n#@$ .#\n#    #\n######");
+        // The bottom right corner can never be left again
+        assert_eq!(grid.push_path(8, 16), Err(PushPlanError::DeadSquare));
+        assert_eq!(grid.push_path(8, 0), Err(PushPlanError::Blocked));
+    }
+
+    #[test]
+    fn boxes_are_parked_on_switches_to_get_through_gates() {
+        let grid = grid("########\n#@$a.  #\n##A#####\n#   $. #\n########");
+        let pushes = grid.solve(MAX_STATES).unwrap();
+        // The box has to stay on the switch until the room behind the gate is done
+        assert_eq!(pushes[0], Push { box_index: 10, direction: Direction::Right });
+        assert_eq!(pushes[1], Push { box_index: 28, direction: Direction::Right });
+        assert_eq!(pushes.len(), 3);
+    }
+
+    #[test]
+    fn boxes_on_switches_keep_their_gates_open_in_corrals() {
+        let mut board = load("########\n#@$a.  #\n##A#####\n#   $. #\n########");
+        let dead = SolverGrid::from_board(&board).dead_squares();
+        // Parking the box on the switch is the first push of the solution
+        board.try_move(Direction::Right);
+        assert_eq!(SolverGrid::from_board(&board).deadlock(&dead), None);
+    }
+
+    #[test]
+    fn boxes_slide_over_ice_and_fill_holes() {
+        let mut board = load("#######\n# @   #\n#  $  #\n# $x~.#\n#######");
+        let pushes = SolverGrid::from_board(&board).solve(MAX_STATES).unwrap();
+        // One box fills the hole, the other slides over the ice onto the goal
+        assert_eq!(pushes.len(), 3);
+        for push in pushes {
+            let grid = SolverGrid::from_board(&board);
+            board.player = grid.step(push.box_index, push.direction.opposite()).unwrap();
+            assert!(matches!(board.try_move(push.direction), MoveOutcome::Pushed { .. }));
+        }
+        assert!(board.triggers_filled());
+    }
+
+    #[test]
+    fn boxes_cross_filled_holes() {
+        // Either box only gets to the goal over the hole the other one fills
+        let mut board = load("########\n#@$x  .#\n# #$####\n#   ####\n########");
+        let pushes = SolverGrid::from_board(&board).solve(MAX_STATES).unwrap();
+        for push in pushes {
+            let grid = SolverGrid::from_board(&board);
+            board.player = grid.step(push.box_index, push.direction.opposite()).unwrap();
+            assert!(matches!(board.try_move(push.direction), MoveOutcome::Pushed { .. }));
+        }
+        assert!(board.is_solved());
+    }
+}
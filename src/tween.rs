@@ -0,0 +1,126 @@
+use bevy::prelude::*;
+
+use crate::components::{MoveTimer, Player, WorldPosition, MOVE_SECONDS};
+
+// How a tween spreads its way over its duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    // Slow at both ends
+    EaseInOut,
+    // Fast at first, settling into place
+    EaseOut,
+}
+
+impl Easing {
+    // Share of the way covered after a share of the time, both from 0 to 1
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0., 1.);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => t * t * (3. - 2. * t),
+            Easing::EaseOut => 1. - (1. - t) * (1. - t),
+        }
+    }
+}
+
+// Moves are eased in and out, so every tile step reads as one
+const MOVE_EASING: Easing = Easing::EaseInOut;
+
+/// Animates an entity from one point to another. The position only depends
+/// on the time elapsed, so the animation is the same at any frame rate.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Tween {
+    pub start: Vec2,
+    pub end: Vec2,
+    pub elapsed: f32,
+    pub duration: f32,
+    pub easing: Easing,
+}
+
+impl Tween {
+    pub fn new(start: Vec2, end: Vec2, duration: f32, easing: Easing) -> Self {
+        Self { start, end, elapsed: 0., duration, easing }
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.duration <= 0. {
+            1.
+        } else {
+            (self.elapsed / self.duration).min(1.)
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.progress() >= 1.
+    }
+
+    pub fn position(&self) -> Vec2 {
+        self.start.lerp(self.end, self.easing.apply(self.progress()))
+    }
+
+    pub fn tick(&mut self, seconds: f32) {
+        self.elapsed += seconds;
+    }
+}
+
+// Starts a tween towards every world position that changed, from wherever
+// the entity is drawn right now. It takes as long as a move does, so sped up
+// moves are animated faster too.
+pub fn start_tweens(
+    mut commands: Commands,
+    move_timer_query: Query<&MoveTimer, With<Player>>,
+    query: Query<(Entity, &Transform, &WorldPosition), Changed<WorldPosition>>) {
+
+    let duration = move_timer_query.get_single()
+        .map_or(MOVE_SECONDS, |timer| timer.duration().as_secs_f32());
+    for (entity, transform, world_pos) in &query {
+        let end = Vec2::new(world_pos.x, world_pos.y);
+        commands.entity(entity)
+            .insert(Tween::new(transform.translation.truncate(), end, duration, MOVE_EASING));
+    }
+}
+
+pub fn animate_tweens(time: Res<Time>, mut query: Query<(&mut Tween, &mut Transform)>) {
+    for (mut tween, mut transform) in &mut query {
+        if tween.finished() {
+            continue;
+        }
+        tween.tick(time.delta_seconds());
+        // Snapped to whole pixels, so sprites don't blur between them
+        let position = tween.position().round();
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn easings_start_and_end_in_place() {
+        for easing in [Easing::Linear, Easing::EaseInOut, Easing::EaseOut] {
+            assert_eq!(easing.apply(0.), 0.);
+            assert_eq!(easing.apply(1.), 1.);
+            assert_eq!(easing.apply(2.), 1.);
+        }
+        assert!(Easing::EaseOut.apply(0.5) > 0.5);
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+    }
+
+    #[test]
+    fn tweens_only_depend_on_the_time_elapsed() {
+        let tween = Tween::new(Vec2::ZERO, Vec2::new(8., 0.), 0.4, Easing::EaseInOut);
+        let at_rate = |fps: f32, seconds: f32| {
+            let mut tween = tween;
+            for _ in 0..(seconds * fps).round() as usize {
+                tween.tick(1. / fps);
+            }
+            tween.position()
+        };
+        assert!(at_rate(30., 1. / 6.).distance(at_rate(144., 1. / 6.)) < 1e-3);
+        assert_eq!(at_rate(30., 1.), Vec2::new(8., 0.));
+        assert_eq!(at_rate(144., 1.), Vec2::new(8., 0.));
+    }
+}